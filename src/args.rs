@@ -0,0 +1,28 @@
+use anyhow::{Result, bail};
+
+/// Removes `--flag value` or `--flag=value` from `args` and returns the value.
+pub fn take_flag(args: &mut Vec<String>, flag: &str) -> Result<Option<String>> {
+    let prefix = format!("{flag}=");
+    let Some(i) = args
+        .iter()
+        .position(|arg| arg == flag || arg.starts_with(&prefix))
+    else {
+        return Ok(None);
+    };
+
+    let arg = args.remove(i);
+    if let Some(value) = arg.strip_prefix(&prefix) {
+        Ok(Some(value.to_string()))
+    } else if i < args.len() {
+        Ok(Some(args.remove(i)))
+    } else {
+        bail!("{flag} requires a value");
+    }
+}
+
+/// Removes every `--flag` from `args` and returns whether there was one.
+pub fn take_switch(args: &mut Vec<String>, flag: &str) -> bool {
+    let len = args.len();
+    args.retain(|arg| arg != flag);
+    args.len() != len
+}
@@ -0,0 +1,29 @@
+use std::env::{self, args};
+
+use anyhow::{Context, Result, bail};
+use http_file_uploader::{UploadClient, args::take_flag, logger};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    logger::initialize(true, Some(module_path!()));
+
+    let mut args = args().skip(1).collect::<Vec<_>>();
+    let delete_token = take_flag(&mut args, "--token")?;
+
+    if args.is_empty() {
+        bail!("No file names or URLs provided.");
+    }
+
+    // deleting by full URL with a delete token works without any configuration
+    let upload_token = match &delete_token {
+        Some(_) => env::var("UPLOAD_TOKEN").unwrap_or_default(),
+        None => env::var("UPLOAD_TOKEN").context("UPLOAD_TOKEN must be set")?,
+    };
+    let client = UploadClient::new(&env::var("URL").unwrap_or_default(), &upload_token);
+
+    for name_or_url in args {
+        client.delete(&name_or_url, delete_token.as_deref()).await?;
+    }
+
+    Ok(())
+}
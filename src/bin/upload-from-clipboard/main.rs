@@ -1,14 +1,16 @@
 mod clipboard;
 
-use std::{env::args, sync::LazyLock};
+use std::{env::args, io::stdout, path::PathBuf, sync::LazyLock};
 
 use anyhow::{Context, Result, bail};
-use http_file_uploader::{guess_ext_from_reader_peek, logger, upload, upload_files};
+use http_file_uploader::{
+    UploadBody, UploadClient, UploadOptions, args::take_switch, check_upload_results,
+    guess_ext_from_reader_peek, logger, upload_files, write_json_results, write_result,
+};
 use mime::{
     IMAGE_BMP, IMAGE_JPEG, IMAGE_PNG, TEXT_HTML, TEXT_HTML_UTF_8, TEXT_PLAIN, TEXT_PLAIN_UTF_8,
 };
 use mime_guess::Mime;
-use reqwest::Body;
 use tokio_util::io::ReaderStream;
 use tracing::{debug, warn};
 use url::Url;
@@ -35,12 +37,19 @@ static BEST_MIME_EXTS: LazyLock<Vec<(Mime, Option<&str>)>> = LazyLock::new(|| {
 async fn main() -> Result<()> {
     logger::initialize(true, Some(module_path!()));
 
+    let mut args = args().skip(1).collect::<Vec<_>>();
+    let options = UploadOptions::take_from_args(&mut args)?;
+    let fail_fast = take_switch(&mut args, "--fail-fast");
+    let json = take_switch(&mut args, "--json");
+    let client = UploadClient::from_env()?;
+    let mut args = args.into_iter();
+
     let existing_mimes = get_existing_mimes()
         .await
         .context("failed to get existing mimes")?;
     debug!(?existing_mimes);
 
-    let (mime, maybe_ext) = match args().nth(1) {
+    let (mime, maybe_ext) = match args.next() {
         Some(s) => {
             let mime: Mime = s.parse()?;
 
@@ -56,7 +65,7 @@ async fn main() -> Result<()> {
                 })
                 .unwrap_or(mime);
 
-            let maybe_ext = if let Some(ext) = args().nth(2) {
+            let maybe_ext = if let Some(ext) = args.next() {
                 Some(ext)
             } else {
                 BEST_MIME_EXTS.iter().find_map(|(best_mime, maybe_ext)| {
@@ -100,11 +109,13 @@ async fn main() -> Result<()> {
     };
 
     if mime != "text/uri-list" {
-        // normal clipboard content, stream it
+        // normal clipboard content, spool it so failed uploads can be retried
 
         get_clipboard_stream(mime.as_ref(), |stdout| {
             let mime = mime.clone();
             let maybe_ext = maybe_ext.clone();
+            let options = &options;
+            let client = &client;
             async move {
                 let (ext, body) = if let Some(ext) = maybe_ext.or_else(|| {
                     mime_guess::get_mime_extensions(&mime).and_then(|exts| {
@@ -112,16 +123,28 @@ async fn main() -> Result<()> {
                         exts.first().map(|s| s.to_string())
                     })
                 }) {
-                    (ext, Body::wrap_stream(ReaderStream::new(stdout)))
+                    (ext, UploadBody::spool(ReaderStream::new(stdout)).await?)
                 } else {
                     // guess extension from first MiB
                     let (ext, stream) = guess_ext_from_reader_peek(stdout).await?;
-                    (ext, Body::wrap_stream(stream))
+                    (ext, UploadBody::spool(stream).await?)
                 };
 
                 debug!(?mime, ?ext);
 
-                upload(body, &ext).await.context("failed to upload")?;
+                let result = client
+                    .upload(body, &ext, options)
+                    .await
+                    .context("failed to upload");
+                if json {
+                    // listed like stdin, since clipboard contents have no path
+                    let results = [(PathBuf::from("-"), result)];
+                    write_json_results(&mut std::io::stdout(), &results)?;
+                    let [(_, result)] = results;
+                    result?;
+                } else {
+                    write_result(&mut std::io::stdout(), &result?)?;
+                }
 
                 Ok(())
             }
@@ -132,37 +155,55 @@ async fn main() -> Result<()> {
 
         let output = get_clipboard_output(mime.as_ref()).await?;
         let output = output.trim();
-        let paths = output
-            .split_ascii_whitespace()
-            .map(|s| {
-                let url: Url = s
-                    .parse()
-                    .with_context(|| format!("failed to parse URI {s:?}"))?;
-
-                if url.scheme() != "file" {
-                    bail!("skipping non-file URI: {url}");
+        let uris = output.split_ascii_whitespace().collect::<Vec<_>>();
+        let total = uris.len();
+
+        // entries that can't be uploaded count as failed uploads
+        let mut results = Vec::new();
+        let mut paths = Vec::new();
+        for uri in uris {
+            match file_path(uri) {
+                Ok(path) => paths.push(path),
+                Err(e) => {
+                    warn!("Failed to upload {uri}: {e}");
+                    results.push((PathBuf::from(uri), Err(e)));
                 }
-
-                let path = url
-                    .to_file_path()
-                    .map_err(|_| anyhow::anyhow!("failed to convert URL to file path: {url:?}"))?;
-                if !path.exists() {
-                    bail!("file does not exist: {path:?}");
+            }
+        }
+        if !fail_fast || results.is_empty() {
+            results.extend(upload_files(&client, paths, &options, fail_fast).await);
+        }
+        if json {
+            write_json_results(&mut stdout(), &results)?;
+        } else {
+            for (_, result) in &results {
+                if let Ok(result) = result {
+                    write_result(&mut stdout(), result)?;
                 }
+            }
+        }
+        check_upload_results(&results, total)?;
+    }
 
-                Ok(path)
-            })
-            .filter_map(|result| match result {
-                Ok(path) => Some(path),
-                Err(e) => {
-                    warn!("{e}");
-                    None
-                }
-            })
-            .collect::<Vec<_>>();
+    Ok(())
+}
+
+/// The existing file a `text/uri-list` entry points to.
+fn file_path(uri: &str) -> Result<PathBuf> {
+    let url: Url = uri
+        .parse()
+        .with_context(|| format!("failed to parse URI {uri:?}"))?;
 
-        upload_files(paths).await?;
+    if url.scheme() != "file" {
+        bail!("not a file URI: {url}");
     }
 
-    Ok(())
+    let path = url
+        .to_file_path()
+        .map_err(|_| anyhow::anyhow!("failed to convert URL to file path: {url:?}"))?;
+    if !path.exists() {
+        bail!("file does not exist: {path:?}");
+    }
+
+    Ok(path)
 }
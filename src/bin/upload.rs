@@ -1,13 +1,26 @@
-use std::{env::args, io::IsTerminal, path::PathBuf};
+use std::{
+    env::args,
+    io::{IsTerminal, stdout},
+    path::PathBuf,
+};
 
 use anyhow::{Result, bail};
-use http_file_uploader::{logger, upload_files};
+use http_file_uploader::{
+    UploadClient, UploadOptions, args::take_switch, check_upload_results, logger, upload_files,
+    write_json_results, write_result,
+};
 
 #[tokio::main]
 async fn main() -> Result<()> {
     logger::initialize(true, Some(module_path!()));
 
-    let mut paths = args().skip(1).map(PathBuf::from).collect::<Vec<_>>();
+    let mut args = args().skip(1).collect::<Vec<_>>();
+    let options = UploadOptions::take_from_args(&mut args)?;
+    let fail_fast = take_switch(&mut args, "--fail-fast");
+    let json = take_switch(&mut args, "--json");
+    let client = UploadClient::from_env()?;
+
+    let mut paths = args.into_iter().map(PathBuf::from).collect::<Vec<_>>();
 
     if paths.is_empty() {
         if std::io::stdin().is_terminal() {
@@ -17,7 +30,18 @@ async fn main() -> Result<()> {
         }
     }
 
-    upload_files(paths).await?;
+    let total = paths.len();
+    let results = upload_files(&client, paths, &options, fail_fast).await;
+    if json {
+        write_json_results(&mut stdout(), &results)?;
+    } else {
+        for (_, result) in &results {
+            if let Ok(result) = result {
+                write_result(&mut stdout(), result)?;
+            }
+        }
+    }
+    check_upload_results(&results, total)?;
 
     Ok(())
 }
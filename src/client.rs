@@ -0,0 +1,558 @@
+use std::{
+    env, fmt, io,
+    ops::Range,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Error, Result, bail, ensure};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use bytes::Bytes;
+use futures::{Stream, TryStreamExt};
+use percent_encoding::{NON_ALPHANUMERIC, utf8_percent_encode};
+use reqwest::{Body, RequestBuilder, Response, StatusCode, header::HeaderMap};
+use serde::{Deserialize, Serialize};
+use tempfile::{NamedTempFile, TempPath};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufWriter, SeekFrom},
+};
+use tokio_util::io::ReaderStream;
+use tracing::{debug, info, warn};
+
+use crate::{UploadOptions, unix_time};
+
+const JSON: &str = "application/json";
+/// Version of the [tus](https://tus.io/protocols/resumable-upload) protocol we speak.
+const TUS_VERSION: &str = "1.0.0";
+const OFFSET_CONTENT_TYPE: &str = "application/offset+octet-stream";
+
+/// Talks to one server, reusing connections between requests.
+#[derive(Debug, Clone)]
+pub struct UploadClient {
+    client: reqwest::Client,
+    url: String,
+    upload_token: String,
+    retry: RetryPolicy,
+    chunk_size: u64,
+}
+
+/// What the server told us about a finished upload.
+///
+/// Servers without JSON responses only tell us the name, expiry and delete token.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadResult {
+    /// Full URL the upload can be downloaded from.
+    pub url: String,
+    /// Name of the upload on the server, including the extension.
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// SHA-256 of the contents in hex.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// What the upload is served as.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mime: Option<String>,
+    #[serde(
+        default,
+        with = "unix_time::option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub expires_at: Option<SystemTime>,
+    /// Lets whoever has it delete the upload early.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delete_token: Option<String>,
+}
+
+/// How often and how patiently transient failures are retried.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    /// Wait before the first retry, doubled for every retry after it.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn backoff(&self, retry: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_backoff)
+    }
+}
+
+/// Data to upload that can be read again from the start, so failed uploads can be retried.
+#[derive(Debug)]
+pub enum UploadBody {
+    Bytes(Bytes),
+    File(PathBuf),
+    /// A stream written to a temporary file, deleted once this is dropped.
+    Spooled(TempPath),
+}
+
+impl UploadBody {
+    /// Writes `stream` to a temporary file so it can be sent more than once.
+    pub async fn spool<S>(stream: S) -> Result<Self>
+    where
+        S: Stream<Item = io::Result<Bytes>>,
+    {
+        let (f, temp_path) = NamedTempFile::new()
+            .context("failed to create spool file")?
+            .into_parts();
+        let mut writer = BufWriter::new(File::from_std(f));
+        tokio::pin!(stream);
+        while let Some(chunk) = stream.try_next().await? {
+            writer.write_all(&chunk).await?;
+        }
+        writer.flush().await?;
+
+        Ok(Self::Spooled(temp_path))
+    }
+
+    async fn len(&self) -> Result<u64> {
+        match self {
+            Self::Bytes(bytes) => Ok(bytes.len() as u64),
+            Self::File(path) => Ok(tokio::fs::metadata(path)
+                .await
+                .with_context(|| format!("failed to read {path:?}"))?
+                .len()),
+            Self::Spooled(path) => Ok(tokio::fs::metadata(path).await?.len()),
+        }
+    }
+
+    /// A request body of the bytes in `range`.
+    async fn body(&self, range: Range<u64>) -> Result<Body> {
+        let path = match self {
+            Self::Bytes(bytes) => {
+                return Ok(bytes.slice(range.start as usize..range.end as usize).into());
+            }
+            Self::File(path) => path.as_path(),
+            Self::Spooled(path) => path,
+        };
+
+        let mut f = File::open(path)
+            .await
+            .with_context(|| format!("failed to open {path:?}"))?;
+        f.seek(SeekFrom::Start(range.start)).await?;
+        let reader = f.take(range.end - range.start);
+
+        Ok(Body::wrap_stream(ReaderStream::new(reader)))
+    }
+}
+
+impl From<Bytes> for UploadBody {
+    fn from(bytes: Bytes) -> Self {
+        Self::Bytes(bytes)
+    }
+}
+
+impl From<&'static str> for UploadBody {
+    fn from(s: &'static str) -> Self {
+        Self::Bytes(Bytes::from_static(s.as_bytes()))
+    }
+}
+
+impl From<String> for UploadBody {
+    fn from(s: String) -> Self {
+        Self::Bytes(s.into())
+    }
+}
+
+impl From<Vec<u8>> for UploadBody {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self::Bytes(bytes.into())
+    }
+}
+
+/// Whether a failed request is worth sending again, and how long the server asked us to wait.
+enum Failure {
+    Transient(Error, Option<Duration>),
+    Permanent(Error),
+}
+
+impl UploadClient {
+    pub fn new(url: &str, upload_token: &str) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url: url.trim_end_matches('/').to_string(),
+            upload_token: upload_token.to_string(),
+            retry: RetryPolicy::default(),
+            chunk_size: 8 * 1024 * 1024, // 8 MiB
+        }
+    }
+
+    /// Reads the server URL from `URL` and the token from `UPLOAD_TOKEN`.
+    pub fn from_env() -> Result<Self> {
+        let upload_token = env::var("UPLOAD_TOKEN").context("UPLOAD_TOKEN must be set")?;
+        let url = env::var("URL").context("URL must be set")?;
+
+        Ok(Self::new(&url, &upload_token))
+    }
+
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Uploads larger than this are sent in chunks of this size, so a failure only
+    /// resends the chunk it interrupted.
+    pub fn with_chunk_size(mut self, chunk_size: u64) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Uploads `body`, retrying connection errors, 5xx and 429 responses with backoff.
+    pub async fn upload(
+        &self,
+        body: UploadBody,
+        ext: &str,
+        options: &UploadOptions,
+    ) -> Result<UploadResult> {
+        let len = body.len().await?;
+        debug!(?ext, len, "uploading");
+
+        if len > self.chunk_size {
+            match self.upload_resumable(&body, len, ext, options).await? {
+                Some(result) => return Ok(result),
+                None => debug!("server doesn't support resumable uploads"),
+            }
+        }
+
+        let res = self
+            .send_with_retry(|| async {
+                let req = self
+                    .authorized(self.client.post(format!("{}/upload.{ext}", self.url)))
+                    .header("Accept", JSON)
+                    .header("Content-Length", len)
+                    .body(body.body(0..len).await?);
+                Ok(with_options(req, options))
+            })
+            .await?;
+
+        self.upload_result(res).await
+    }
+
+    /// Sends `body` through a tus upload at `/files`, or returns `None` if the server has
+    /// no resumable uploads.
+    async fn upload_resumable(
+        &self,
+        body: &UploadBody,
+        len: u64,
+        ext: &str,
+        options: &UploadOptions,
+    ) -> Result<Option<UploadResult>> {
+        let create_url = format!("{}/files", self.url);
+        let metadata = format!("ext {}", BASE64_STANDARD.encode(ext));
+        let res = self
+            .send_with_retry(|| async {
+                let req = self
+                    .tus(self.client.post(&create_url))
+                    .header("Upload-Length", len)
+                    .header("Upload-Metadata", &metadata);
+                Ok(with_options(req, options))
+            })
+            .await;
+        let res = match res {
+            Ok(res) => res,
+            Err(e)
+                if e.downcast_ref::<StatusError>()
+                    .is_some_and(|e| e.status == StatusCode::NOT_FOUND) =>
+            {
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        };
+        let location = res
+            .headers()
+            .get("location")
+            .and_then(|value| value.to_str().ok())
+            .context("server didn't send the location of the upload")?;
+        let session_url = res.url().join(location)?;
+        debug!(%session_url, "started resumable upload");
+
+        let mut offset = 0;
+        let mut failures = 0;
+        loop {
+            let end = (offset + self.chunk_size).min(len);
+            let result = async {
+                self.tus(self.client.patch(session_url.clone()))
+                    .header("Content-Type", OFFSET_CONTENT_TYPE)
+                    .header("Upload-Offset", offset)
+                    .header("Content-Length", end - offset)
+                    .body(body.body(offset..end).await?)
+                    .send()
+                    .await
+                    .map_err(Error::from)
+            }
+            .await;
+
+            let failure = match result {
+                Ok(res) if res.status().is_success() => {
+                    // only the last append names the finished upload
+                    if let Some(name) = header(res.headers(), "upload-name") {
+                        let mut result = self.result_from_headers(res.headers(), name);
+                        result.size = Some(len);
+                        return Ok(Some(result));
+                    }
+                    offset = upload_offset(&res)?;
+                    failures = 0;
+                    continue;
+                }
+                Ok(res) => classify_session_response(res).await,
+                Err(e) => Failure::Transient(e, None),
+            };
+            let (e, retry_after) = match failure {
+                Failure::Transient(e, retry_after) if failures < self.retry.max_retries => {
+                    (e, retry_after)
+                }
+                Failure::Transient(e, _) | Failure::Permanent(e) => return Err(e),
+            };
+
+            let wait = retry_after.unwrap_or_else(|| self.retry.backoff(failures));
+            failures += 1;
+            warn!("Resuming upload at offset {offset} in {wait:?} after: {e:#}");
+            tokio::time::sleep(wait).await;
+
+            let res = self
+                .send_with_retry_as(
+                    || async { Ok(self.tus(self.client.head(session_url.clone()))) },
+                    classify_session_response,
+                )
+                .await?;
+            offset = upload_offset(&res)?;
+        }
+    }
+
+    /// Deletes an upload by name or full URL, using its delete token or the upload token.
+    pub async fn delete(&self, name_or_url: &str, delete_token: Option<&str>) -> Result<()> {
+        let token = delete_token.unwrap_or(&self.upload_token);
+        let delete_url = if name_or_url.contains("://") {
+            name_or_url.to_string()
+        } else {
+            ensure!(
+                !self.url.is_empty(),
+                "a server URL is needed to delete by name"
+            );
+            format!("{}/{name_or_url}", self.url)
+        };
+
+        debug!(?delete_url, "deleting");
+        self.client
+            .delete(&delete_url)
+            .header("Authorization", format!("Bearer {token}"))
+            .send()
+            .await?
+            .error_for_status()?;
+        info!("deleted {delete_url}");
+
+        Ok(())
+    }
+
+    fn authorized(&self, req: RequestBuilder) -> RequestBuilder {
+        req.header("Authorization", format!("Bearer {}", self.upload_token))
+    }
+
+    fn tus(&self, req: RequestBuilder) -> RequestBuilder {
+        self.authorized(req).header("Tus-Resumable", TUS_VERSION)
+    }
+
+    /// Sends the request built by `make_request` until it succeeds, fails permanently, or
+    /// runs out of retries.
+    async fn send_with_retry<F, Fut>(&self, make_request: F) -> Result<Response>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<RequestBuilder>>,
+    {
+        self.send_with_retry_as(make_request, classify_response)
+            .await
+    }
+
+    /// Like [`Self::send_with_retry`], with `classify` telling which failed responses are
+    /// worth retrying.
+    async fn send_with_retry_as<F, Fut, C, CFut>(
+        &self,
+        make_request: F,
+        classify: C,
+    ) -> Result<Response>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<RequestBuilder>>,
+        C: Fn(Response) -> CFut,
+        CFut: Future<Output = Failure>,
+    {
+        let mut retry = 0;
+        loop {
+            let failure = match make_request().await?.send().await {
+                Ok(res) if res.status().is_success() => return Ok(res),
+                Ok(res) => classify(res).await,
+                Err(e) => Failure::Transient(e.into(), None),
+            };
+            match failure {
+                Failure::Transient(e, retry_after) if retry < self.retry.max_retries => {
+                    let wait = retry_after.unwrap_or_else(|| self.retry.backoff(retry));
+                    retry += 1;
+                    warn!("Retrying in {wait:?} after: {e:#}");
+                    tokio::time::sleep(wait).await;
+                }
+                Failure::Transient(e, _) | Failure::Permanent(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn upload_result(&self, res: Response) -> Result<UploadResult> {
+        if header(res.headers(), "content-type").is_some_and(|content_type| content_type == JSON) {
+            let body = res.bytes().await?;
+            return serde_json::from_slice(&body).context("failed to parse upload response");
+        }
+
+        let headers = res.headers().clone();
+        let name = res.text().await?;
+
+        Ok(self.result_from_headers(&headers, name))
+    }
+
+    /// The result of an upload named `name`, as far as the response headers tell.
+    fn result_from_headers(&self, headers: &HeaderMap, name: String) -> UploadResult {
+        let expires_at = header(headers, "expires-at")
+            .and_then(|secs| secs.parse().ok())
+            .map(|secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs));
+
+        UploadResult {
+            url: format!("{}/{name}", self.url),
+            name,
+            size: None,
+            sha256: header(headers, "upload-sha256"),
+            mime: header(headers, "upload-mime"),
+            expires_at,
+            delete_token: header(headers, "delete-token"),
+        }
+    }
+}
+
+fn header(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string())
+}
+
+fn with_options(mut req: RequestBuilder, options: &UploadOptions) -> RequestBuilder {
+    if let Some(expire) = options.expire {
+        req = req.header("Expires-In", format!("{}s", expire.as_secs()));
+    }
+    if let Some(max_downloads) = options.max_downloads {
+        req = req.header("Max-Downloads", max_downloads);
+    }
+    if let Some(naming) = &options.naming {
+        req = req.header("Naming-Strategy", naming);
+    }
+    if let Some(filename) = &options.filename {
+        let filename = utf8_percent_encode(filename, NON_ALPHANUMERIC).to_string();
+        req = req.header("Upload-Filename", filename);
+    }
+    if let Some(name) = &options.name {
+        req = req.header("Desired-Name", name);
+        if options.suffix_on_conflict {
+            req = req.header("Name-Conflict", "suffix");
+        }
+    }
+    req
+}
+
+async fn classify_response(res: Response) -> Failure {
+    let status = res.status();
+    let retry_after = res
+        .headers()
+        .get("retry-after")
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_retry_after);
+    let e = response_error(res).await;
+
+    if status.is_server_error()
+        || status == StatusCode::TOO_MANY_REQUESTS
+        || status == StatusCode::REQUEST_TIMEOUT
+    {
+        Failure::Transient(e, retry_after)
+    } else {
+        Failure::Permanent(e)
+    }
+}
+
+/// Like [`classify_response`] for requests to a resumable session, where a conflict
+/// means we're out of sync with the server, and a locked session is still busy with a
+/// chunk that failed on our end. Either way, asking the server where to continue sorts
+/// it out.
+async fn classify_session_response(res: Response) -> Failure {
+    if res.status() == StatusCode::CONFLICT || res.status() == StatusCode::LOCKED {
+        return Failure::Transient(response_error(res).await, None);
+    }
+
+    classify_response(res).await
+}
+
+/// A response the server sent instead of what we asked for.
+#[derive(Debug)]
+struct StatusError {
+    status: StatusCode,
+    message: String,
+}
+
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "server responded with {}: {}", self.status, self.message)
+    }
+}
+
+impl std::error::Error for StatusError {}
+
+async fn response_error(res: Response) -> Error {
+    let status = res.status();
+    let message = res.text().await.unwrap_or_default();
+    StatusError {
+        status,
+        message: message.trim().to_string(),
+    }
+    .into()
+}
+
+fn upload_offset(res: &Response) -> Result<u64> {
+    match res
+        .headers()
+        .get("upload-offset")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+    {
+        Some(offset) => Ok(offset),
+        None => bail!("server didn't send a valid upload offset"),
+    }
+}
+
+/// Parses the delay form of `Retry-After`; dates fall back to the usual backoff.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    value.trim().parse().ok().map(Duration::from_secs)
+}
+
+#[test]
+fn test_retry_backoff() {
+    let retry = RetryPolicy::default();
+    assert_eq!(retry.backoff(0), Duration::from_millis(500));
+    assert_eq!(retry.backoff(2), Duration::from_secs(2));
+    assert_eq!(retry.backoff(20), retry.max_backoff);
+    assert_eq!(parse_retry_after(" 3"), Some(Duration::from_secs(3)));
+    assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"), None);
+}
@@ -0,0 +1,46 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+
+/// Parses durations like `90s`, `30m`, `1h`, `7d` or `2w`; a bare number is seconds.
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let s = s.trim();
+    let (number, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let number: u64 = number
+        .parse()
+        .with_context(|| format!("invalid duration {s:?}"))?;
+
+    let multiplier = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        "w" => 60 * 60 * 24 * 7,
+        _ => bail!("invalid duration unit {unit:?} in {s:?}"),
+    };
+    let secs = number
+        .checked_mul(multiplier)
+        .with_context(|| format!("duration {s:?} is too large"))?;
+
+    Ok(Duration::from_secs(secs))
+}
+
+#[test]
+fn test_parse_duration() {
+    assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+    assert_eq!(parse_duration("90s").unwrap(), Duration::from_secs(90));
+    assert_eq!(parse_duration("30m").unwrap(), Duration::from_secs(30 * 60));
+    assert_eq!(parse_duration("1h").unwrap(), Duration::from_secs(60 * 60));
+    assert_eq!(
+        parse_duration("30d").unwrap(),
+        Duration::from_secs(30 * 86400)
+    );
+    assert_eq!(
+        parse_duration("2w").unwrap(),
+        Duration::from_secs(14 * 86400)
+    );
+    assert!(parse_duration("").is_err());
+    assert!(parse_duration("h").is_err());
+    assert!(parse_duration("1y").is_err());
+    assert!(parse_duration("-1h").is_err());
+}
@@ -1,53 +1,88 @@
+pub mod args;
+mod client;
+pub mod duration;
 pub mod logger;
+pub mod server;
+mod unix_time;
 
 use std::{
-    env,
-    io::{IsTerminal, stdout},
-    path::PathBuf,
+    io::{self, IsTerminal, Write},
+    path::{Path, PathBuf},
+    time::Duration,
 };
 
-use anyhow::{Context, Error, Result};
+use anyhow::{Context, Error, Result, bail};
 use bytes::Bytes;
 use futures::StreamExt;
-use reqwest::Body;
+use serde::Serialize;
 use tokio::{
     fs::File,
     io::{AsyncRead, AsyncReadExt, BufReader, stdin},
 };
 use tokio_stream::Stream;
 use tokio_util::io::ReaderStream;
-use tracing::{debug, warn};
-
-pub async fn upload(body: Body, ext: &str) -> Result<()> {
-    let upload_token = env::var("UPLOAD_TOKEN").context("UPLOAD_TOKEN must be set")?;
-    let url = env::var("URL").context("URL must be set")?;
-
-    let url = url.trim_end_matches('/');
-    let upload_url = format!("{url}/upload.{ext}");
-
-    debug!(?ext, "uploading");
-    let client = reqwest::Client::new();
-    let res = client
-        .post(upload_url)
-        .header("Authorization", format!("Bearer {upload_token}"))
-        .body(body)
-        .send()
-        .await?;
-    let res = res.error_for_status()?;
-    let text = res.text().await?;
-
-    if stdout().is_terminal() {
-        println!("{url}/{text}");
-    } else {
-        print!("{url}/{text}");
-    }
+use tracing::{debug, info, warn};
 
-    Ok(())
+pub use crate::client::{RetryPolicy, UploadBody, UploadClient, UploadResult};
+use crate::{
+    args::{take_flag, take_switch},
+    duration::parse_duration,
+};
+
+#[derive(Debug, Clone, Default)]
+pub struct UploadOptions {
+    /// How long the server should keep the upload, bounded by the server's maximum.
+    pub expire: Option<Duration>,
+    /// Delete the upload after it has been downloaded in full this many times. Range
+    /// requests, revalidations and aborted downloads don't count.
+    pub max_downloads: Option<u32>,
+    /// Ask for a naming strategy like `words`, `base62:12`, `hash` or `sequential`.
+    pub naming: Option<String>,
+    /// Ask for a specific name, without the extension.
+    pub name: Option<String>,
+    /// Append `-2`, `-3`, ... to `name` if it's taken instead of failing.
+    pub suffix_on_conflict: bool,
+    /// Name of the uploaded file, which the server keeps in the upload's metadata.
+    pub filename: Option<String>,
+}
+
+impl UploadOptions {
+    /// Removes flags like `--expire 1h`, `--max-downloads 1`, `--naming base62` or
+    /// `--name release-notes --suffix` from `args`, leaving positional arguments behind.
+    pub fn take_from_args(args: &mut Vec<String>) -> Result<Self> {
+        let mut options = Self::default();
+        if let Some(expire) = take_flag(args, "--expire")? {
+            options.expire = Some(parse_duration(&expire)?);
+        }
+        if let Some(max_downloads) = take_flag(args, "--max-downloads")? {
+            let max_downloads = max_downloads
+                .parse()
+                .ok()
+                .filter(|&max_downloads| max_downloads > 0)
+                .context("--max-downloads must be a positive number")?;
+            options.max_downloads = Some(max_downloads);
+        }
+        options.naming = take_flag(args, "--naming")?;
+        options.name = take_flag(args, "--name")?;
+        options.suffix_on_conflict = take_switch(args, "--suffix");
+
+        Ok(options)
+    }
 }
 
-pub async fn upload_files(paths: Vec<PathBuf>) -> Result<()> {
-    let _ = tokio_stream::iter(paths)
-        .map(|path| async move {
+/// Uploads every path, where `-` is stdin, returning the result of each in the order
+/// of `paths`.
+///
+/// With `fail_fast`, uploads still in progress are cancelled after the first failure and
+/// the remaining paths are left out of the results.
+pub async fn upload_files(
+    client: &UploadClient,
+    paths: Vec<PathBuf>,
+    options: &UploadOptions,
+    fail_fast: bool,
+) -> Vec<(PathBuf, Result<UploadResult>)> {
+    let mut uploads = tokio_stream::iter(paths.into_iter().enumerate())
+        .map(|(i, path)| async move {
             let result = {
                 let path = path.to_path_buf();
                 async move {
@@ -56,53 +91,140 @@ pub async fn upload_files(paths: Vec<PathBuf>) -> Result<()> {
                         let stdin = stdin();
                         let stdin = BufReader::new(stdin);
                         let (ext, stream) = guess_ext_from_reader_peek(stdin).await?;
-                        (ext, Body::wrap_stream(stream))
+                        // spool stdin so a failed upload can be sent again
+                        (ext, UploadBody::spool(stream).await?)
                     } else {
-                        let maybe_ext = if let Some(ext) = path.extension() {
-                            // use ext from path
-                            let ext = ext.to_str().with_context(|| {
-                                format!("failed to convert extension to str: {ext:?}")
-                            })?;
-                            Some(ext.to_string())
-                        } else {
-                            None
-                        };
+                        let mut f = File::open(&path).await.context("failed to open file")?;
 
-                        let f =
-                            BufReader::new(File::open(&path).await.context("failed to open file")?);
-
-                        if let Some(ext) = maybe_ext {
-                            (ext, Body::wrap_stream(ReaderStream::new(f)))
+                        let ext = if let Some(ext) = path.extension() {
+                            // use ext from path
+                            ext.to_str()
+                                .with_context(|| {
+                                    format!("failed to convert extension to str: {ext:?}")
+                                })?
+                                .to_string()
                         } else {
                             debug!("peeking file to see if it's utf8...");
                             // peek file to see if it's text, else use "bin"
+                            let mut first_chunk = Vec::with_capacity(1024 * 1024); // 1 MiB
+                            (&mut f)
+                                .take(first_chunk.capacity() as u64)
+                                .read_to_end(&mut first_chunk)
+                                .await?;
+                            guess_ext_from_bytes(&first_chunk)
+                        };
 
-                            let (ext, stream) = guess_ext_from_reader_peek(f).await?;
-                            (ext, Body::wrap_stream(stream))
-                        }
+                        (ext, UploadBody::File(path.clone()))
                     };
 
-                    upload(body, &ext).await.context("failed to upload")?;
+                    let options = UploadOptions {
+                        filename: path
+                            .file_name()
+                            .filter(|_| path.as_os_str() != "-")
+                            .map(|name| name.to_string_lossy().into_owned()),
+                        ..options.clone()
+                    };
+                    let result = client
+                        .upload(body, &ext, &options)
+                        .await
+                        .context("failed to upload")?;
 
-                    Ok::<_, Error>(())
+                    Ok::<_, Error>(result)
                 }
             }
             .await;
 
-            (path, result)
+            (i, path, result)
         })
-        .buffer_unordered(4)
-        .filter_map(|(path, result)| async move {
-            match result {
-                Ok(v) => Some(v),
-                Err(e) => {
-                    warn!("Failed to upload file {path:?} {e:?}");
-                    None
-                }
-            }
+        .buffer_unordered(4);
+
+    let mut results = Vec::new();
+    while let Some((i, path, result)) = uploads.next().await {
+        if let Err(e) = &result {
+            warn!("Failed to upload file {path:?} {e:?}");
+        }
+        let failed = result.is_err();
+        results.push((i, path, result));
+        if failed && fail_fast {
+            break;
+        }
+    }
+    results.sort_by_key(|(i, ..)| *i);
+
+    results
+        .into_iter()
+        .map(|(_, path, result)| (path, result))
+        .collect()
+}
+
+/// Fails with a summary if fewer than `total` uploads succeeded.
+pub fn check_upload_results(
+    results: &[(PathBuf, Result<UploadResult>)],
+    total: usize,
+) -> Result<()> {
+    let failed = results
+        .iter()
+        .filter(|(_, result)| result.is_err())
+        .map(|(path, _)| format!("{path:?}"))
+        .collect::<Vec<_>>();
+    let skipped = total - results.len();
+    if failed.is_empty() && skipped == 0 {
+        return Ok(());
+    }
+
+    let mut summary = format!(
+        "{} of {total} uploads failed: {}",
+        failed.len(),
+        failed.join(", ")
+    );
+    if skipped > 0 {
+        summary.push_str(&format!(", {skipped} skipped"));
+    }
+    bail!(summary);
+}
+
+/// Writes the URL of an upload to `out`, without a trailing newline unless it's a
+/// terminal so it can be piped as is. The delete token goes to the log.
+pub fn write_result<W>(out: &mut W, result: &UploadResult) -> io::Result<()>
+where
+    W: Write + IsTerminal,
+{
+    if let Some(delete_token) = &result.delete_token {
+        info!("delete {} with token {delete_token}", result.url);
+    }
+
+    if out.is_terminal() {
+        writeln!(out, "{}", result.url)
+    } else {
+        write!(out, "{}", result.url)
+    }
+}
+
+/// Writes `results` to `out` as one JSON array for scripts, each entry holding the path
+/// along with either the upload or the error.
+pub fn write_json_results<W: Write>(
+    out: &mut W,
+    results: &[(PathBuf, Result<UploadResult>)],
+) -> Result<()> {
+    #[derive(Serialize)]
+    struct Entry<'a> {
+        path: &'a Path,
+        #[serde(flatten)]
+        upload: Option<&'a UploadResult>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    }
+
+    let entries = results
+        .iter()
+        .map(|(path, result)| Entry {
+            path,
+            upload: result.as_ref().ok(),
+            error: result.as_ref().err().map(|e| format!("{e:#}")),
         })
-        .collect::<Vec<_>>()
-        .await;
+        .collect::<Vec<_>>();
+    serde_json::to_writer(&mut *out, &entries)?;
+    writeln!(out)?;
 
     Ok(())
 }
@@ -164,3 +286,63 @@ where
 
     Ok((maybe_ext, stream))
 }
+
+#[test]
+fn test_take_options_from_args() {
+    let mut args = ["--max-downloads", "2", "notes.md"]
+        .map(String::from)
+        .to_vec();
+    let options = UploadOptions::take_from_args(&mut args).unwrap();
+    assert_eq!(options.max_downloads, Some(2));
+    assert_eq!(args, ["notes.md"]);
+
+    for max_downloads in ["0", "-1", "many"] {
+        let mut args = vec!["--max-downloads".to_string(), max_downloads.to_string()];
+        assert!(UploadOptions::take_from_args(&mut args).is_err());
+    }
+}
+
+#[tokio::test]
+async fn test_upload_files_reports_failures() {
+    let client = UploadClient::new("http://127.0.0.1:1", "test");
+    let paths = vec![
+        PathBuf::from("missing-1.txt"),
+        PathBuf::from("missing-2.txt"),
+    ];
+
+    let results = upload_files(&client, paths.clone(), &Default::default(), false).await;
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0].0, paths[0]);
+    assert!(results.iter().all(|(_, result)| result.is_err()));
+    assert!(check_upload_results(&results, 2).is_err());
+
+    let results = upload_files(&client, paths, &Default::default(), true).await;
+    assert_eq!(results.len(), 1);
+    assert!(check_upload_results(&[], 0).is_ok());
+}
+
+#[test]
+fn test_write_json_results() {
+    let results = vec![
+        (
+            PathBuf::from("a.txt"),
+            Ok(UploadResult {
+                url: "http://localhost/a.txt".to_string(),
+                name: "a.txt".to_string(),
+                size: None,
+                sha256: None,
+                mime: None,
+                expires_at: None,
+                delete_token: None,
+            }),
+        ),
+        (PathBuf::from("b.txt"), Err(anyhow::anyhow!("gone"))),
+    ];
+
+    let mut out = Vec::new();
+    write_json_results(&mut out, &results).unwrap();
+    let entries: serde_json::Value = serde_json::from_slice(&out).unwrap();
+    assert_eq!(entries[0]["path"], "a.txt");
+    assert_eq!(entries[0]["name"], "a.txt");
+    assert_eq!(entries[1]["error"], "gone");
+}
@@ -1,24 +1,44 @@
-mod logger;
-mod server;
+use std::{env, path::PathBuf};
 
-use std::env;
-
-use anyhow::{Context, Result};
-use futures::future;
-
-use crate::server::run_server;
+use anyhow::{Result, bail};
+use http_file_uploader::{
+    args::{take_flag, take_switch},
+    logger,
+    server::{Config, Server, Storage},
+};
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let upload_token = env::var("UPLOAD_TOKEN").context("UPLOAD_TOKEN must be set")?;
-    let port = env::var("PORT")
-        .unwrap_or_else(|_| "3030".to_string())
-        .parse::<u16>()
-        .context("PORT must be a valid u16")?;
+    let mut args = env::args().skip(1).collect::<Vec<_>>();
+    let config_path = take_flag(&mut args, "--config")?.map(PathBuf::from);
+    let ephemeral = take_switch(&mut args, "--ephemeral");
+    if let Some(arg) = args.first() {
+        bail!("unexpected argument {arg:?}");
+    }
+
+    // fail before touching the storage directory if the config is invalid
+    let config = Config::load(config_path.as_deref())?;
 
     logger::initialize(true, Some(module_path!()));
 
-    run_server(port, upload_token, future::pending()).await;
+    // uploads silently vanishing on restart is worse than refusing to start
+    let storage = match (&config.storage_dir, ephemeral) {
+        (Some(dir), false) => Storage::persistent(dir).await?,
+        (None, true) => Storage::ephemeral()?,
+        (Some(_), true) => bail!("--ephemeral can't be combined with a storage directory"),
+        (None, false) => bail!(
+            "STORAGE_DIR or storage_dir in the config file must be set, or --ephemeral passed \
+             to keep uploads in a temporary directory that is deleted on shutdown"
+        ),
+    };
+
+    let server = Server::builder(config)
+        .storage(storage)
+        .handle_signals(true)
+        .reload_with(move || Config::load(config_path.as_deref()))
+        .start()
+        .await?;
+    server.wait().await?;
 
     Ok(())
 }
@@ -0,0 +1,241 @@
+use std::{collections::HashSet, path::Path, sync::Arc, time::Duration};
+
+use anyhow::{Context, Result, ensure};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    duration::parse_duration,
+    server::{
+        config::{Config, parse_size},
+        utils::to_hex,
+    },
+};
+
+/// Generates a random secret that is handed out once and only stored hashed.
+pub fn generate_token() -> String {
+    to_hex(&rand::random::<[u8; 16]>())
+}
+
+pub fn hash_token(token: &str) -> String {
+    to_hex(&Sha256::digest(token.as_bytes()))
+}
+
+/// Compares two hashes without returning early, so timing doesn't reveal how much matched.
+pub fn hashes_match(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// What uploads made with one token are allowed to do.
+#[derive(Debug, Clone)]
+pub struct TokenPolicy {
+    /// Names the token in logs and upload metadata.
+    pub label: String,
+    token_hash: String,
+    /// Applied on top of the server-wide `max_upload_size`.
+    pub max_upload_size: Option<u64>,
+    /// Extensions uploads may have, any extension is allowed if `None`.
+    pub extensions: Option<Vec<String>>,
+    /// Applied on top of the server-wide maximum retention.
+    pub max_retention: Option<Duration>,
+    /// Whether the token can delete any upload, not just ones it has the delete token of.
+    pub may_delete: bool,
+}
+
+impl TokenPolicy {
+    /// A token without any limits beyond the server-wide ones.
+    fn unrestricted(label: &str, token: &str) -> Self {
+        Self {
+            label: label.to_string(),
+            token_hash: hash_token(token),
+            max_upload_size: None,
+            extensions: None,
+            max_retention: None,
+            may_delete: true,
+        }
+    }
+
+    pub fn allows_extension(&self, ext: &str) -> bool {
+        self.extensions
+            .as_ref()
+            .is_none_or(|extensions| extensions.iter().any(|allowed| allowed == ext))
+    }
+}
+
+/// The label of `UPLOAD_TOKEN`.
+pub const DEFAULT_LABEL: &str = "default";
+
+/// An entry of the tokens file or config file, see [`Tokens::load`].
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TokenEntry {
+    label: String,
+    token_hash: String,
+    #[serde(default)]
+    max_upload_size: Option<String>,
+    #[serde(default)]
+    extensions: Option<Vec<String>>,
+    #[serde(default)]
+    max_retention: Option<String>,
+    #[serde(default)]
+    may_delete: bool,
+}
+
+impl TryFrom<TokenEntry> for TokenPolicy {
+    type Error = anyhow::Error;
+
+    fn try_from(entry: TokenEntry) -> Result<Self> {
+        let TokenEntry {
+            label,
+            token_hash,
+            max_upload_size,
+            extensions,
+            max_retention,
+            may_delete,
+        } = entry;
+        ensure!(!label.is_empty(), "label must not be empty");
+        ensure!(
+            token_hash.len() == 64 && token_hash.bytes().all(|b| b.is_ascii_hexdigit()),
+            "token_hash of {label:?} must be a hex encoded SHA-256 hash"
+        );
+
+        Ok(Self {
+            token_hash: token_hash.to_ascii_lowercase(),
+            max_upload_size: max_upload_size
+                .map(|size| parse_size(&size))
+                .transpose()
+                .with_context(|| format!("invalid max_upload_size of {label:?}"))?,
+            extensions: extensions.map(|extensions| {
+                extensions
+                    .into_iter()
+                    .map(|ext| ext.trim_start_matches('.').to_string())
+                    .collect()
+            }),
+            max_retention: max_retention
+                .map(|retention| parse_duration(&retention))
+                .transpose()
+                .with_context(|| format!("invalid max_retention of {label:?}"))?,
+            may_delete,
+            label,
+        })
+    }
+}
+
+/// Every token that may upload to the server.
+#[derive(Clone)]
+pub struct Tokens {
+    policies: Arc<Vec<Arc<TokenPolicy>>>,
+}
+
+impl Tokens {
+    /// Combines `upload_token`, which gets no limits beyond the server-wide ones, with the
+    /// tokens from the config file and `tokens_file`.
+    ///
+    /// The tokens file holds a JSON array of tokens like
+    /// `{"label": "ci", "token_hash": "<sha256 of the token>", "max_upload_size": "10M",
+    /// "extensions": ["png"], "max_retention": "1d", "may_delete": false}`,
+    /// where everything but the label and hash is optional.
+    pub async fn load(config: &Config) -> Result<Self> {
+        let mut policies = Vec::new();
+        if let Some(token) = &config.upload_token {
+            policies.push(TokenPolicy::unrestricted(DEFAULT_LABEL, token));
+        }
+        policies.extend(config.tokens.iter().cloned());
+        if let Some(path) = &config.tokens_file {
+            policies.extend(load_tokens_file(path).await?);
+        }
+
+        let mut labels = HashSet::new();
+        for policy in &policies {
+            ensure!(
+                labels.insert(&policy.label),
+                "duplicate token label {:?}",
+                policy.label
+            );
+        }
+        ensure!(!policies.is_empty(), "at least one upload token is needed");
+
+        Ok(Self {
+            policies: Arc::new(policies.into_iter().map(Arc::new).collect()),
+        })
+    }
+
+    /// Finds the policy of the token labeled `label`.
+    pub fn get(&self, label: &str) -> Option<Arc<TokenPolicy>> {
+        self.policies
+            .iter()
+            .find(|policy| policy.label == label)
+            .cloned()
+    }
+
+    /// Finds the policy of a token, checking every known token so the time taken
+    /// doesn't depend on which one matched.
+    pub fn authenticate(&self, token: &str) -> Option<Arc<TokenPolicy>> {
+        let hash = hash_token(token);
+        self.policies.iter().fold(None, |found, policy| {
+            if hashes_match(&policy.token_hash, &hash) {
+                Some(policy.clone())
+            } else {
+                found
+            }
+        })
+    }
+}
+
+async fn load_tokens_file(path: &Path) -> Result<Vec<TokenPolicy>> {
+    let contents = tokio::fs::read(path)
+        .await
+        .with_context(|| format!("failed to read tokens file {path:?}"))?;
+    let entries: Vec<TokenEntry> = serde_json::from_slice(&contents)
+        .with_context(|| format!("failed to parse tokens file {path:?}"))?;
+
+    entries
+        .into_iter()
+        .map(|entry| {
+            TokenPolicy::try_from(entry)
+                .with_context(|| format!("invalid entry in tokens file {path:?}"))
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn test_tokens_file() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let path = temp_dir.path().join("tokens.json");
+    let hash = hash_token("secret");
+    tokio::fs::write(
+        &path,
+        format!(
+            r#"[{{"label": "ci", "token_hash": "{hash}", "max_upload_size": "1K",
+                 "extensions": [".png"], "max_retention": "1h"}}]"#
+        ),
+    )
+    .await
+    .unwrap();
+
+    let mut config = Config {
+        upload_token: Some("admin".to_string()),
+        tokens_file: Some(path.clone()),
+        ..Default::default()
+    };
+    let tokens = Tokens::load(&config).await.unwrap();
+    assert!(tokens.authenticate("admin").unwrap().may_delete);
+    let policy = tokens.authenticate("secret").unwrap();
+    assert_eq!(policy.label, "ci");
+    assert_eq!(policy.max_upload_size, Some(1024));
+    assert_eq!(policy.max_retention, Some(Duration::from_secs(60 * 60)));
+    assert!(policy.allows_extension("png"));
+    assert!(!policy.allows_extension("txt"));
+    assert!(!policy.may_delete);
+    assert!(tokens.authenticate("wrong").is_none());
+
+    tokio::fs::write(&path, r#"[{"label": "ci", "token_hash": "abc"}]"#)
+        .await
+        .unwrap();
+    config.upload_token = None;
+    assert!(Tokens::load(&config).await.is_err());
+}
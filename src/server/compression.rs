@@ -0,0 +1,161 @@
+use std::{fmt, path::Path, pin::Pin, str::FromStr};
+
+use anyhow::{Error, Result, bail};
+use async_compression::{
+    Level,
+    tokio::{
+        bufread::{BrotliDecoder, GzipDecoder, ZstdDecoder},
+        write::{BrotliEncoder, GzipEncoder, ZstdEncoder},
+    },
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+};
+
+/// How an upload is compressed at rest, which is also the `Content-Encoding` it can be
+/// served with as is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    Gzip,
+    Zstd,
+    Brotli,
+}
+
+impl Encoding {
+    /// The `Content-Encoding` token.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Zstd => "zstd",
+            Self::Brotli => "br",
+        }
+    }
+
+    /// Wraps `writer` so everything written to it gets compressed.
+    fn encoder<'a, W>(self, writer: W) -> Pin<Box<dyn AsyncWrite + Send + 'a>>
+    where
+        W: AsyncWrite + Send + 'a,
+    {
+        match self {
+            Self::Gzip => Box::pin(GzipEncoder::with_quality(writer, Level::Default)),
+            Self::Zstd => Box::pin(ZstdEncoder::with_quality(writer, Level::Default)),
+            // the default level is far too slow for uploads
+            Self::Brotli => Box::pin(BrotliEncoder::with_quality(writer, Level::Precise(5))),
+        }
+    }
+
+    /// Wraps `reader` so it yields the decompressed contents.
+    pub fn decoder<R>(self, reader: R) -> Pin<Box<dyn AsyncRead + Send + Sync>>
+    where
+        R: AsyncRead + Send + Sync + 'static,
+    {
+        let reader = BufReader::new(reader);
+        match self {
+            Self::Gzip => Box::pin(GzipDecoder::new(reader)),
+            Self::Zstd => Box::pin(ZstdDecoder::new(reader)),
+            Self::Brotli => Box::pin(BrotliDecoder::new(reader)),
+        }
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "gzip" => Self::Gzip,
+            "zstd" => Self::Zstd,
+            "brotli" | "br" => Self::Brotli,
+            _ => bail!("unknown compression {s:?}"),
+        })
+    }
+}
+
+/// Whether uploads of type `mime` are worth compressing, which excludes formats that
+/// are already compressed like images, video and archives.
+pub fn is_compressible(mime: &str) -> bool {
+    mime.starts_with("text/")
+        || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+        || matches!(
+            mime,
+            "application/json"
+                | "application/xml"
+                | "application/javascript"
+                | "application/x-javascript"
+                | "application/toml"
+                | "application/yaml"
+                | "application/x-yaml"
+                | "application/x-sh"
+                | "application/sql"
+                | "image/svg+xml"
+        )
+}
+
+/// Whether an `Accept-Encoding` header allows responses compressed with `encoding`.
+pub fn accepts(accept_encoding: &str, encoding: Encoding) -> bool {
+    accept_encoding.split(',').any(|coding| {
+        let mut params = coding.split(';').map(str::trim);
+        let name = params.next().unwrap_or_default();
+        let rejected = params.any(|param| {
+            param
+                .strip_prefix("q=")
+                .and_then(|q| q.parse::<f32>().ok())
+                .is_some_and(|q| q <= 0.0)
+        });
+        (name.eq_ignore_ascii_case(encoding.as_str()) || name == "*") && !rejected
+    })
+}
+
+/// Compresses `src` into `dst`, returning the compressed size.
+pub async fn compress_file(src: &Path, dst: &mut File, encoding: Encoding) -> Result<u64> {
+    let mut src = File::open(src).await?;
+    let mut encoder = encoding.encoder(&mut *dst);
+    tokio::io::copy(&mut src, &mut encoder).await?;
+    encoder.shutdown().await?;
+    drop(encoder);
+
+    Ok(dst.metadata().await?.len())
+}
+
+#[tokio::test]
+async fn test_compress_round_trip() {
+    use tokio::io::AsyncReadExt;
+
+    let temp_dir = tempfile::tempdir().unwrap();
+    let src = temp_dir.path().join("log.txt");
+    let contents = "the same line over and over\n".repeat(100);
+    tokio::fs::write(&src, &contents).await.unwrap();
+
+    for encoding in [Encoding::Gzip, Encoding::Zstd, Encoding::Brotli] {
+        let dst = temp_dir.path().join(encoding.as_str());
+        let mut f = File::create(&dst).await.unwrap();
+        let size = compress_file(&src, &mut f, encoding).await.unwrap();
+        assert!(size < contents.len() as u64 / 10);
+        let mut decompressed = String::new();
+        encoding
+            .decoder(File::open(&dst).await.unwrap())
+            .read_to_string(&mut decompressed)
+            .await
+            .unwrap();
+        assert_eq!(decompressed, contents);
+    }
+}
+
+#[test]
+fn test_accepts() {
+    assert!(accepts("gzip, deflate, br, zstd", Encoding::Brotli));
+    assert!(accepts("GZIP;q=0.5", Encoding::Gzip));
+    assert!(accepts("*", Encoding::Zstd));
+    assert!(!accepts("gzip;q=0", Encoding::Gzip));
+    assert!(!accepts("identity", Encoding::Gzip));
+}
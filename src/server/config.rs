@@ -0,0 +1,476 @@
+use std::{
+    env,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Result, bail, ensure};
+use serde::Deserialize;
+
+use crate::{
+    duration::parse_duration,
+    server::{
+        auth::{TokenEntry, TokenPolicy},
+        compression::Encoding,
+        encryption::Keyring,
+        naming::StrategyKind,
+        postprocessing::Postprocessors,
+    },
+};
+
+pub struct Config {
+    /// Addresses the server listens on.
+    pub bind: Vec<SocketAddr>,
+    /// Uploads go to an ephemeral directory that is deleted on shutdown if unset, which
+    /// the server binary only allows with `--ephemeral`.
+    pub storage_dir: Option<PathBuf>,
+    /// Where clients reach the server, used for the URLs in JSON responses.
+    ///
+    /// Taken from the `Host` and `X-Forwarded-*` headers of each request if unset.
+    pub public_url: Option<String>,
+    /// A token without limits beyond the ones below, labelled `default`.
+    pub upload_token: Option<String>,
+    /// JSON file of hashed tokens with their own limits, see [`Tokens::load`].
+    ///
+    /// [`Tokens::load`]: crate::server::auth::Tokens::load
+    pub tokens_file: Option<PathBuf>,
+    /// Tokens given in the config file itself.
+    pub tokens: Vec<TokenPolicy>,
+    pub retention: Retention,
+    /// Uploads larger than this are rejected with a 413.
+    pub max_upload_size: Option<u64>,
+    /// Total size all uploads together may take up.
+    pub storage_quota: Option<u64>,
+    /// Delete the oldest uploads to make room instead of rejecting new ones once
+    /// `storage_quota` is reached.
+    pub evict_on_quota: bool,
+    /// Longest extension accepted in `/upload.<ext>`.
+    pub max_extension_length: usize,
+    /// How uploads are named unless the uploader asks for something else.
+    pub naming: StrategyKind,
+    /// One file of words per position in word names, the built-in lists are used if empty.
+    pub word_lists: Vec<PathBuf>,
+    pub postprocessors: Postprocessors,
+    /// Directory whose `md.html`, `html.html` and `html_head.html` replace the built-in
+    /// templates of the postprocessors, see [`Templates::load`].
+    ///
+    /// [`Templates::load`]: crate::server::postprocessing::Templates::load
+    pub templates_dir: Option<PathBuf>,
+    /// Text-like uploads are stored compressed like this, if it makes them smaller.
+    pub compression: Option<Encoding>,
+    /// Uploads are encrypted at rest with the current key if set, and rewritten with it
+    /// if they're stored with an old key or in plain.
+    pub encryption: Keyring,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind: vec![SocketAddr::from(([0, 0, 0, 0], 3030))],
+            storage_dir: None,
+            public_url: None,
+            upload_token: None,
+            tokens_file: None,
+            tokens: Vec::new(),
+            retention: Retention::default(),
+            max_upload_size: None,
+            storage_quota: None,
+            evict_on_quota: false,
+            max_extension_length: 10,
+            naming: StrategyKind::default(),
+            word_lists: Vec::new(),
+            postprocessors: Postprocessors::default(),
+            templates_dir: None,
+            compression: None,
+            encryption: Keyring::default(),
+        }
+    }
+}
+
+/// The layout of the TOML config file, where everything is optional.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    bind: Option<Vec<SocketAddr>>,
+    storage_dir: Option<PathBuf>,
+    public_url: Option<String>,
+    upload_token: Option<String>,
+    tokens_file: Option<PathBuf>,
+    tokens: Vec<TokenEntry>,
+    retention: RetentionSection,
+    limits: LimitsSection,
+    naming: NamingSection,
+    postprocessors: Option<Vec<String>>,
+    templates_dir: Option<PathBuf>,
+    compression: Option<String>,
+    encryption: EncryptionSection,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RetentionSection {
+    default: Option<String>,
+    max: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LimitsSection {
+    max_upload_size: Option<String>,
+    storage_quota: Option<String>,
+    evict_on_quota: Option<bool>,
+    max_extension_length: Option<usize>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct EncryptionSection {
+    key: Option<String>,
+    old_keys: Option<Vec<String>>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct NamingSection {
+    strategy: Option<String>,
+    word_lists: Option<Vec<PathBuf>>,
+}
+
+impl Config {
+    /// Reads the TOML file at `path` if given, then lets environment variables
+    /// override what it sets.
+    ///
+    /// Relative paths in the file are resolved against the directory it's in.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let mut config = Self::default();
+        if let Some(path) = path {
+            let contents = std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read config file {path:?}"))?;
+            let file: ConfigFile = toml::from_str(&contents)
+                .with_context(|| format!("failed to parse config file {path:?}"))?;
+            config
+                .apply_file(file, path.parent().unwrap_or(Path::new("")))
+                .with_context(|| format!("invalid config file {path:?}"))?;
+        }
+        config.apply_env()?;
+
+        if let Some(public_url) = &mut config.public_url {
+            public_url.truncate(public_url.trim_end_matches('/').len());
+        }
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Checks what can't be expressed in the types, for configs that weren't loaded
+    /// through [`Config::load`] as well.
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            self.upload_token.is_some() || self.tokens_file.is_some() || !self.tokens.is_empty(),
+            "UPLOAD_TOKEN, TOKENS_FILE or tokens in the config file must be set"
+        );
+        if let Some(public_url) = &self.public_url {
+            ensure!(
+                public_url.starts_with("http://") || public_url.starts_with("https://"),
+                "the public URL must start with http:// or https://"
+            );
+        }
+        ensure!(!self.bind.is_empty(), "at least one bind address is needed");
+        ensure!(
+            self.retention.default <= self.retention.max,
+            "the default retention must not be longer than the maximum retention"
+        );
+
+        Ok(())
+    }
+
+    fn apply_file(&mut self, file: ConfigFile, base_dir: &Path) -> Result<()> {
+        if let Some(bind) = file.bind {
+            self.bind = bind;
+        }
+        if let Some(storage_dir) = file.storage_dir {
+            self.storage_dir = Some(base_dir.join(storage_dir));
+        }
+        if let Some(public_url) = file.public_url {
+            self.public_url = Some(public_url);
+        }
+        if let Some(upload_token) = file.upload_token {
+            self.upload_token = Some(upload_token);
+        }
+        if let Some(tokens_file) = file.tokens_file {
+            self.tokens_file = Some(base_dir.join(tokens_file));
+        }
+        self.tokens = file
+            .tokens
+            .into_iter()
+            .map(TokenPolicy::try_from)
+            .collect::<Result<_>>()
+            .context("invalid token")?;
+
+        if let Some(default) = file.retention.default {
+            self.retention.default =
+                parse_duration(&default).context("invalid retention.default")?;
+        }
+        if let Some(max) = file.retention.max {
+            self.retention.max = parse_duration(&max).context("invalid retention.max")?;
+        }
+
+        if let Some(size) = file.limits.max_upload_size {
+            self.max_upload_size =
+                Some(parse_size(&size).context("invalid limits.max_upload_size")?);
+        }
+        if let Some(size) = file.limits.storage_quota {
+            self.storage_quota = Some(parse_size(&size).context("invalid limits.storage_quota")?);
+        }
+        if let Some(evict_on_quota) = file.limits.evict_on_quota {
+            self.evict_on_quota = evict_on_quota;
+        }
+        if let Some(max_extension_length) = file.limits.max_extension_length {
+            self.max_extension_length = max_extension_length;
+        }
+
+        if let Some(strategy) = file.naming.strategy {
+            self.naming = strategy.parse().context("invalid naming.strategy")?;
+        }
+        if let Some(word_lists) = file.naming.word_lists {
+            self.word_lists = word_lists
+                .into_iter()
+                .map(|path| base_dir.join(path))
+                .collect();
+        }
+
+        if let Some(postprocessors) = file.postprocessors {
+            self.postprocessors =
+                Postprocessors::from_names(&postprocessors).context("invalid postprocessors")?;
+        }
+        if let Some(templates_dir) = file.templates_dir {
+            self.templates_dir = Some(base_dir.join(templates_dir));
+        }
+        if let Some(compression) = file.compression {
+            self.compression = parse_compression(&compression).context("invalid compression")?;
+        }
+        if let Some(key) = file.encryption.key {
+            self.encryption.current = Some(key.parse().context("invalid encryption.key")?);
+        }
+        if let Some(old_keys) = file.encryption.old_keys {
+            self.encryption.old = old_keys
+                .iter()
+                .map(|key| key.parse())
+                .collect::<Result<_>>()
+                .context("invalid encryption.old_keys")?;
+        }
+
+        Ok(())
+    }
+
+    fn apply_env(&mut self) -> Result<()> {
+        if let Ok(bind) = env::var("BIND") {
+            self.bind = bind
+                .split(',')
+                .map(|addr| addr.trim().parse())
+                .collect::<Result<_, _>>()
+                .context("BIND must be a comma separated list of socket addresses")?;
+        }
+        if let Ok(port) = env::var("PORT") {
+            let port = port.parse::<u16>().context("PORT must be a valid u16")?;
+            for addr in &mut self.bind {
+                addr.set_port(port);
+            }
+        }
+        if let Some(storage_dir) = env::var_os("STORAGE_DIR") {
+            self.storage_dir = Some(storage_dir.into());
+        }
+        if let Ok(public_url) = env::var("PUBLIC_URL") {
+            self.public_url = Some(public_url);
+        }
+        if let Ok(upload_token) = env::var("UPLOAD_TOKEN") {
+            self.upload_token = Some(upload_token);
+        }
+        if let Some(tokens_file) = env::var_os("TOKENS_FILE") {
+            self.tokens_file = Some(tokens_file.into());
+        }
+
+        if let Ok(default) = env::var("DEFAULT_RETENTION") {
+            self.retention.default =
+                parse_duration(&default).context("DEFAULT_RETENTION must be a valid duration")?;
+        }
+        if let Ok(max) = env::var("MAX_RETENTION") {
+            self.retention.max =
+                parse_duration(&max).context("MAX_RETENTION must be a valid duration")?;
+        }
+
+        if let Ok(size) = env::var("MAX_UPLOAD_SIZE") {
+            self.max_upload_size =
+                Some(parse_size(&size).context("MAX_UPLOAD_SIZE must be a valid size")?);
+        }
+        if let Ok(size) = env::var("STORAGE_QUOTA") {
+            self.storage_quota =
+                Some(parse_size(&size).context("STORAGE_QUOTA must be a valid size")?);
+        }
+        if let Ok(evict_on_quota) = env::var("EVICT_ON_QUOTA") {
+            self.evict_on_quota = evict_on_quota == "1" || evict_on_quota == "true";
+        }
+        if let Ok(length) = env::var("MAX_EXTENSION_LENGTH") {
+            self.max_extension_length = length
+                .parse()
+                .context("MAX_EXTENSION_LENGTH must be a number")?;
+        }
+
+        if let Ok(naming) = env::var("NAMING_STRATEGY") {
+            self.naming = naming
+                .parse()
+                .context("NAMING_STRATEGY must be a valid naming strategy")?;
+        }
+        if let Some(paths) = env::var_os("WORD_LISTS") {
+            self.word_lists = env::split_paths(&paths).collect();
+        }
+
+        if let Ok(postprocessors) = env::var("POSTPROCESSORS") {
+            let names = postprocessors
+                .split(',')
+                .map(|name| name.trim().to_string())
+                .filter(|name| !name.is_empty())
+                .collect::<Vec<_>>();
+            self.postprocessors = Postprocessors::from_names(&names)
+                .context("POSTPROCESSORS must be a comma separated list of postprocessors")?;
+        }
+        if let Some(templates_dir) = env::var_os("TEMPLATES_DIR") {
+            self.templates_dir = Some(templates_dir.into());
+        }
+        if let Ok(compression) = env::var("COMPRESSION") {
+            self.compression = parse_compression(&compression)
+                .context("COMPRESSION must be gzip, zstd, brotli or none")?;
+        }
+        if let Ok(key) = env::var("ENCRYPTION_KEY") {
+            self.encryption.current = Some(
+                key.parse()
+                    .context("ENCRYPTION_KEY must be 32 bytes of base64")?,
+            );
+        }
+        if let Ok(old_keys) = env::var("OLD_ENCRYPTION_KEYS") {
+            self.encryption.old = old_keys
+                .split(',')
+                .filter(|key| !key.trim().is_empty())
+                .map(str::parse)
+                .collect::<Result<_>>()
+                .context("OLD_ENCRYPTION_KEYS must be a comma separated list of keys")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Parses `gzip`, `zstd` or `brotli`, where `none` turns compression off.
+fn parse_compression(s: &str) -> Result<Option<Encoding>> {
+    match s.trim() {
+        "" | "none" => Ok(None),
+        s => s.parse().map(Some),
+    }
+}
+
+/// Parses sizes like `512`, `64K`, `10MiB` or `1G` into bytes, using powers of 1024.
+pub fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let (number, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let number: u64 = number
+        .parse()
+        .with_context(|| format!("invalid size {s:?}"))?;
+
+    let shift = match unit.trim_end_matches("iB").trim_end_matches('B') {
+        "" => 0,
+        "K" | "k" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => bail!("invalid size unit {unit:?} in {s:?}"),
+    };
+
+    number
+        .checked_mul(1 << shift)
+        .with_context(|| format!("size {s:?} is too large"))
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Retention {
+    /// Used when the uploader doesn't ask for a specific lifetime.
+    pub default: Duration,
+    /// Longer requested lifetimes are cut down to this.
+    pub max: Duration,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            default: Duration::from_secs(60 * 60 * 24 * 7), // 7 days
+            max: Duration::from_secs(60 * 60 * 24 * 30),    // 30 days
+        }
+    }
+}
+
+impl Retention {
+    pub fn resolve(&self, requested: Option<Duration>) -> Duration {
+        requested.unwrap_or(self.default).min(self.max)
+    }
+}
+
+#[test]
+fn test_parse_size() {
+    assert_eq!(parse_size("512").unwrap(), 512);
+    assert_eq!(parse_size("512B").unwrap(), 512);
+    assert_eq!(parse_size("64K").unwrap(), 64 * 1024);
+    assert_eq!(parse_size("10MiB").unwrap(), 10 * 1024 * 1024);
+    assert_eq!(parse_size("1GB").unwrap(), 1024 * 1024 * 1024);
+    assert!(parse_size("").is_err());
+    assert!(parse_size("1X").is_err());
+}
+
+#[test]
+fn test_config_file() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let path = temp_dir.path().join("server.toml");
+    std::fs::write(
+        &path,
+        r#"
+            bind = ["127.0.0.1:8000", "[::1]:8000"]
+            storage_dir = "uploads"
+            templates_dir = "templates"
+
+            [[tokens]]
+            label = "ci"
+            token_hash = "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b"
+            extensions = ["png"]
+
+            [retention]
+            default = "1d"
+
+            [limits]
+            max_upload_size = "10M"
+            max_extension_length = 4
+
+            [naming]
+            strategy = "base62:8"
+            word_lists = ["adjectives.txt", "nouns.txt"]
+        "#,
+    )
+    .unwrap();
+
+    let mut config = Config::default();
+    let file = toml::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    config.apply_file(file, temp_dir.path()).unwrap();
+    assert_eq!(config.bind.len(), 2);
+    assert_eq!(config.storage_dir, Some(temp_dir.path().join("uploads")));
+    assert_eq!(config.tokens[0].label, "ci");
+    assert_eq!(config.retention.default, Duration::from_secs(60 * 60 * 24));
+    assert_eq!(config.max_upload_size, Some(10 * 1024 * 1024));
+    assert_eq!(config.max_extension_length, 4);
+    assert_eq!(config.naming, StrategyKind::Base62 { length: 8 });
+    assert_eq!(config.word_lists[1], temp_dir.path().join("nouns.txt"));
+    assert!(config.postprocessors.markdown);
+    assert_eq!(
+        config.templates_dir,
+        Some(temp_dir.path().join("templates"))
+    );
+
+    assert!(toml::from_str::<ConfigFile>("port = 3030").is_err());
+}
@@ -0,0 +1,103 @@
+use std::{path::Path, pin::Pin};
+
+use anyhow::Result;
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt},
+};
+use tokio_util::io::ReaderStream;
+use warp::{
+    http::header::{self, HeaderValue},
+    reply::{Reply, Response},
+};
+
+use crate::server::{
+    compression::{Encoding, accepts},
+    encryption::{Keyring, decrypt},
+};
+
+pub type Reader = Pin<Box<dyn AsyncRead + Send + Sync>>;
+
+/// How to read an upload back the way it was stored.
+#[derive(Clone, Copy)]
+pub struct Stored<'a> {
+    /// The compression recorded in the upload's metadata.
+    pub encoding: Option<Encoding>,
+    /// The key recorded in the upload's metadata, which is looked up in `keyring`.
+    pub encrypted_with: Option<&'a str>,
+    pub keyring: &'a Keyring,
+}
+
+impl Stored<'_> {
+    /// Opens the upload at `path` decrypted, but still compressed.
+    pub async fn open_encoded(&self, path: &Path) -> Result<Reader> {
+        Ok(match self.encrypted_with {
+            Some(key_id) => decrypt(path, self.keyring, key_id).await?,
+            None => Box::pin(File::open(path).await?),
+        })
+    }
+
+    /// Opens the plain contents of the upload at `path`.
+    pub async fn open(&self, path: &Path) -> Result<Reader> {
+        let reader = self.open_encoded(path).await?;
+
+        Ok(match self.encoding {
+            Some(encoding) => encoding.decoder(reader),
+            None => reader,
+        })
+    }
+
+    pub async fn read_to_string(&self, path: &Path) -> Result<String> {
+        let mut contents = String::new();
+        self.open(path).await?.read_to_string(&mut contents).await?;
+
+        Ok(contents)
+    }
+
+    /// Serves a compressed upload as is if the client accepts its encoding, and
+    /// decompresses it on the fly otherwise.
+    ///
+    /// Only uploads that aren't encrypted are left to warp, which answers range and
+    /// conditional requests for them.
+    pub async fn serve(
+        &self,
+        f: warp::fs::File,
+        mime: &str,
+        accept_encoding: Option<&str>,
+    ) -> Result<Response> {
+        let accepted = self
+            .encoding
+            .filter(|&encoding| accept_encoding.is_some_and(|header| accepts(header, encoding)));
+
+        let mut resp =
+            if self.encrypted_with.is_none() && (self.encoding.is_none() || accepted.is_some()) {
+                f.into_response()
+            } else {
+                let reader = self.open_encoded(f.path()).await?;
+                match self.encoding {
+                    Some(encoding) if accepted.is_none() => stream(encoding.decoder(reader), mime)?,
+                    _ => stream(reader, mime)?,
+                }
+            };
+        if let Some(encoding) = accepted {
+            resp.headers_mut().insert(
+                header::CONTENT_ENCODING,
+                HeaderValue::from_static(encoding.as_str()),
+            );
+        }
+        if self.encoding.is_some() {
+            resp.headers_mut()
+                .insert(header::VARY, HeaderValue::from_static("accept-encoding"));
+        }
+
+        Ok(resp)
+    }
+}
+
+fn stream(reader: Reader, mime: &str) -> Result<Response> {
+    let mut resp = warp::reply::stream(ReaderStream::new(reader)).into_response();
+    resp.headers_mut()
+        .insert(header::CONTENT_TYPE, HeaderValue::from_str(mime)?);
+
+    Ok(resp)
+}
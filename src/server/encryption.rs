@@ -0,0 +1,356 @@
+use std::{fmt, io, path::Path, pin::Pin, str::FromStr};
+
+use anyhow::{Context, Error, Result, anyhow, ensure};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use bytes::Bytes;
+use chacha20poly1305::{
+    Key, KeyInit, XChaCha20Poly1305,
+    aead::stream::{DecryptorBE32, EncryptorBE32},
+};
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter},
+};
+use tokio_util::io::StreamReader;
+use tracing::{debug, info, warn};
+
+use crate::server::{
+    contents::Stored,
+    state::State,
+    storage::{
+        create_temp_file, is_valid_upload_name, recover_replacement, remove_stale_replacements,
+        replace_upload,
+    },
+    utils::to_hex,
+};
+
+/// Starts every encrypted upload, followed by the ID of its key and the nonce prefix.
+const MAGIC: &[u8; 8] = b"\x89HFUENC\n";
+const KEY_ID_LEN: usize = 8;
+/// What's left of the 24 byte XChaCha20 nonce after the STREAM counter and last flag.
+const NONCE_PREFIX_LEN: usize = 19;
+const HEADER_LEN: usize = MAGIC.len() + KEY_ID_LEN + NONCE_PREFIX_LEN;
+/// Uploads are encrypted in chunks of this much plaintext, so they never have to be
+/// held in memory as a whole.
+const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+
+/// A 256-bit key uploads are encrypted with at rest, given as base64.
+#[derive(Clone)]
+pub struct EncryptionKey {
+    id: [u8; KEY_ID_LEN],
+    key: Key,
+}
+
+impl EncryptionKey {
+    /// Identifies the key in encrypted uploads and their metadata without revealing it.
+    pub fn id(&self) -> String {
+        to_hex(&self.id)
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptionKey")
+            .field("id", &self.id())
+            .finish_non_exhaustive()
+    }
+}
+
+impl FromStr for EncryptionKey {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let key = BASE64_STANDARD
+            .decode(s.trim())
+            .context("encryption key is not base64")?;
+        ensure!(key.len() == 32, "encryption key must be 32 bytes");
+
+        let mut id = [0; KEY_ID_LEN];
+        id.copy_from_slice(&Sha256::digest(&key)[..KEY_ID_LEN]);
+
+        Ok(Self {
+            id,
+            key: *Key::from_slice(&key),
+        })
+    }
+}
+
+/// The key new uploads are encrypted with along with retired keys that older uploads may
+/// still be encrypted with until they're rotated.
+#[derive(Debug, Clone, Default)]
+pub struct Keyring {
+    pub current: Option<EncryptionKey>,
+    pub old: Vec<EncryptionKey>,
+}
+
+impl Keyring {
+    fn get(&self, id: &[u8]) -> Option<&EncryptionKey> {
+        self.current
+            .iter()
+            .chain(&self.old)
+            .find(|key| key.id == id)
+    }
+}
+
+/// Encrypts everything `src` yields into `dst`, returning the encrypted size.
+pub async fn encrypt<R>(mut src: R, dst: &mut File, key: &EncryptionKey) -> Result<u64>
+where
+    R: AsyncRead + Unpin,
+{
+    let nonce = rand::random::<[u8; NONCE_PREFIX_LEN]>();
+    let mut encryptor = EncryptorBE32::from_aead(XChaCha20Poly1305::new(&key.key), (&nonce).into());
+
+    let mut writer = BufWriter::new(&mut *dst);
+    writer.write_all(MAGIC).await?;
+    writer.write_all(&key.id).await?;
+    writer.write_all(&nonce).await?;
+
+    // only the last chunk is shorter than CHUNK_SIZE, possibly empty, so decrypting can
+    // tell a truncated upload from a complete one
+    let mut buf = vec![0; CHUNK_SIZE];
+    loop {
+        let len = read_full(&mut src, &mut buf).await?;
+        if len == CHUNK_SIZE {
+            let chunk = encryptor
+                .encrypt_next(buf.as_slice())
+                .map_err(|_| anyhow!("failed to encrypt upload"))?;
+            writer.write_all(&chunk).await?;
+        } else {
+            let chunk = encryptor
+                .encrypt_last(&buf[..len])
+                .map_err(|_| anyhow!("failed to encrypt upload"))?;
+            writer.write_all(&chunk).await?;
+            break;
+        }
+    }
+    writer.flush().await?;
+    drop(writer);
+
+    Ok(dst.metadata().await?.len())
+}
+
+/// Opens the upload at `path` decrypted with `key_id` from `keyring`, which its metadata
+/// says it's encrypted with.
+pub async fn decrypt(
+    path: &Path,
+    keyring: &Keyring,
+    key_id: &str,
+) -> Result<Pin<Box<dyn AsyncRead + Send + Sync>>> {
+    let mut file = File::open(path).await?;
+    let mut header = [0; HEADER_LEN];
+    let len = read_full(&mut file, &mut header).await?;
+    ensure!(
+        len == HEADER_LEN && &header[..MAGIC.len()] == MAGIC,
+        "encrypted upload is truncated"
+    );
+    let (id, nonce) = header[MAGIC.len()..].split_at(KEY_ID_LEN);
+    // the header can't be trusted to pick the key, it's as much up to the uploader as the
+    // rest of the file
+    ensure!(
+        to_hex(id) == key_id,
+        "upload is encrypted with key {} instead of {key_id}",
+        to_hex(id)
+    );
+    let key = keyring
+        .get(id)
+        .with_context(|| format!("no encryption key with ID {}", to_hex(id)))?;
+    let decryptor = DecryptorBE32::from_aead(XChaCha20Poly1305::new(&key.key), nonce.into());
+
+    let chunks = futures::stream::try_unfold(Some((file, decryptor)), |state| async move {
+        let Some((mut file, mut decryptor)) = state else {
+            return Ok::<_, io::Error>(None);
+        };
+        let invalid = |_| io::Error::new(io::ErrorKind::InvalidData, "failed to decrypt upload");
+
+        let mut buf = vec![0; CHUNK_SIZE + TAG_LEN];
+        let len = read_full(&mut file, &mut buf).await?;
+        if len == buf.len() {
+            let chunk = decryptor.decrypt_next(buf.as_slice()).map_err(invalid)?;
+            Ok(Some((Bytes::from(chunk), Some((file, decryptor)))))
+        } else {
+            let chunk = decryptor.decrypt_last(&buf[..len]).map_err(invalid)?;
+            Ok(Some((Bytes::from(chunk), None)))
+        }
+    });
+
+    Ok(Box::pin(StreamReader::new(chunks)))
+}
+
+/// Rewrites every upload not stored with the current key whenever the keys change, so
+/// old keys can be retired. With encryption turned off, encrypted uploads are decrypted.
+///
+/// The few bytes of overhead encryption adds or removes are only picked up by the quota
+/// on the next start.
+pub async fn run_key_rotation(state: State) {
+    loop {
+        let settings = state.settings();
+        let keyring = &settings.config.encryption;
+        if keyring.current.is_some() || !keyring.old.is_empty() {
+            match rotate_keys(&state, keyring).await {
+                Ok(0) => {}
+                Ok(rotated) => info!("Rewrote {rotated} uploads with the current encryption key"),
+                Err(e) => warn!("Failed to rotate encryption keys: {e}"),
+            }
+        }
+        drop(settings);
+
+        state.key_rotation.notified().await;
+    }
+}
+
+async fn rotate_keys(state: &State, keyring: &Keyring) -> Result<usize> {
+    remove_stale_replacements(&state.dir, &state.metadata).await?;
+
+    let mut rotated = 0;
+    let mut entries = tokio::fs::read_dir(&state.dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        if !is_valid_upload_name(&name) {
+            continue;
+        }
+
+        match rotate_upload(state, keyring, &name).await {
+            Ok(true) => rotated += 1,
+            Ok(false) => {}
+            Err(e) => warn!("Failed to rotate the encryption key of {name}: {e}"),
+        }
+    }
+
+    Ok(rotated)
+}
+
+async fn rotate_upload(state: &State, keyring: &Keyring, name: &str) -> Result<bool> {
+    let Some(mut upload_metadata) = state.metadata.read(name).await? else {
+        return Ok(false);
+    };
+    if upload_metadata.replacing.is_some() {
+        // a previous rotation was cut off
+        recover_replacement(&state.dir, &state.metadata, name).await?;
+        let Some(recovered) = state.metadata.read(name).await? else {
+            return Ok(false);
+        };
+        upload_metadata = recovered;
+    }
+    let key_id = keyring.current.as_ref().map(EncryptionKey::id);
+    if upload_metadata.encrypted_with == key_id {
+        return Ok(false);
+    }
+    debug!("rewriting {name} with encryption key {key_id:?}");
+
+    let stored = Stored {
+        encoding: upload_metadata.encoding,
+        encrypted_with: upload_metadata.encrypted_with.as_deref(),
+        keyring,
+    };
+    let (mut f, temp_path) = create_temp_file(&state.dir).await?;
+    write_with(
+        stored.open_encoded(&state.dir.join(name)).await?,
+        &mut f,
+        keyring.current.as_ref(),
+    )
+    .await?;
+    // the only copy of the upload is about to be replaced with this
+    f.sync_all().await?;
+
+    replace_upload(&state.dir, &state.metadata, name, temp_path, key_id).await
+}
+
+/// Copies what `src` yields to `dst`, encrypted with `key` if given.
+async fn write_with<R>(src: R, dst: &mut File, key: Option<&EncryptionKey>) -> Result<u64>
+where
+    R: AsyncRead + Unpin,
+{
+    match key {
+        Some(key) => encrypt(src, dst, key).await,
+        None => copy_all(src, dst).await,
+    }
+}
+
+async fn copy_all<R, W>(mut src: R, dst: &mut W) -> Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let len = tokio::io::copy(&mut src, dst).await?;
+    dst.flush().await?;
+
+    Ok(len)
+}
+
+/// Reads until `buf` is full or `src` ends, unlike a single read that may stop short.
+async fn read_full<R>(src: &mut R, buf: &mut [u8]) -> io::Result<usize>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut len = 0;
+    while len < buf.len() {
+        match src.read(&mut buf[len..]).await? {
+            0 => break,
+            read => len += read,
+        }
+    }
+
+    Ok(len)
+}
+
+#[tokio::test]
+async fn test_encrypt_round_trip() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let key: EncryptionKey = BASE64_STANDARD.encode([7; 32]).parse().unwrap();
+    let keyring = Keyring {
+        current: Some(key.clone()),
+        old: Vec::new(),
+    };
+
+    // chunk boundaries are where truncation would go unnoticed
+    for len in [0, 10, CHUNK_SIZE, CHUNK_SIZE * 2 + 1] {
+        let contents = (0..len).map(|i| i as u8).collect::<Vec<_>>();
+        let path = temp_dir.path().join(len.to_string());
+        let mut f = File::create(&path).await.unwrap();
+        encrypt(contents.as_slice(), &mut f, &key).await.unwrap();
+
+        let mut decrypted = Vec::new();
+        decrypt(&path, &keyring, &key.id())
+            .await
+            .unwrap()
+            .read_to_end(&mut decrypted)
+            .await
+            .unwrap();
+        assert_eq!(decrypted, contents);
+
+        let encrypted = std::fs::read(&path).unwrap();
+        if len > 0 {
+            let start = &contents[..len.min(16)];
+            assert!(!encrypted.windows(start.len()).any(|w| w == start));
+        }
+        // drop the empty last chunk, or a byte of the last tag
+        let cut = if len == CHUNK_SIZE { TAG_LEN } else { 1 };
+        std::fs::write(&path, &encrypted[..encrypted.len() - cut]).unwrap();
+        let mut truncated = Vec::new();
+        let result = decrypt(&path, &keyring, &key.id())
+            .await
+            .unwrap()
+            .read_to_end(&mut truncated)
+            .await;
+        assert!(result.is_err());
+    }
+
+    let path = temp_dir.path().join("plain");
+    std::fs::write(&path, "not encrypted").unwrap();
+    assert!(decrypt(&path, &keyring, &key.id()).await.is_err());
+
+    // only the key the metadata names may be used, whatever the header says
+    let other: EncryptionKey = BASE64_STANDARD.encode([8; 32]).parse().unwrap();
+    let path = temp_dir.path().join("other");
+    let mut f = File::create(&path).await.unwrap();
+    encrypt(&b"secret"[..], &mut f, &other).await.unwrap();
+    let keyring = Keyring {
+        current: Some(key.clone()),
+        old: vec![other],
+    };
+    assert!(decrypt(&path, &keyring, &key.id()).await.is_err());
+}
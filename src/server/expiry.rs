@@ -0,0 +1,196 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Result;
+use tokio::sync::Notify;
+use tracing::{debug, info, warn};
+
+use crate::server::{
+    metadata::{Metadata, MetadataStore, guess_mime},
+    quota::Quota,
+    storage::{is_hidden_name, remove_upload},
+};
+
+/// Tracks the deadline of every upload and deletes them from a single sweeper task.
+#[derive(Clone)]
+pub struct Expiry {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    dir: PathBuf,
+    metadata: MetadataStore,
+    schedule: Mutex<Schedule>,
+    notify: Notify,
+}
+
+#[derive(Default)]
+struct Schedule {
+    deadlines: BTreeSet<(SystemTime, String)>,
+    by_name: HashMap<String, SystemTime>,
+}
+
+impl Schedule {
+    fn insert(&mut self, name: String, expires_at: SystemTime) {
+        if let Some(previous) = self.by_name.insert(name.clone(), expires_at) {
+            self.deadlines.remove(&(previous, name.clone()));
+        }
+        self.deadlines.insert((expires_at, name));
+    }
+
+    fn remove(&mut self, name: &str) {
+        if let Some(expires_at) = self.by_name.remove(name) {
+            self.deadlines.remove(&(expires_at, name.to_string()));
+        }
+    }
+
+    fn pop_overdue(&mut self, now: SystemTime) -> Option<String> {
+        let (expires_at, _) = self.deadlines.first()?;
+        if *expires_at > now {
+            return None;
+        }
+
+        let (_, name) = self.deadlines.pop_first()?;
+        self.by_name.remove(&name);
+        Some(name)
+    }
+}
+
+impl Expiry {
+    /// Reloads pending deadlines from the sidecar files in `dir`.
+    ///
+    /// Uploads without a sidecar get one based on their modification time and size.
+    pub async fn load(
+        dir: PathBuf,
+        metadata: MetadataStore,
+        default_retention: Duration,
+    ) -> Result<Self> {
+        let mut schedule = Schedule::default();
+
+        let mut entries = tokio::fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            if is_hidden_name(&name) || !entry.file_type().await?.is_file() {
+                continue;
+            }
+
+            let expires_at = match metadata.read(&name).await {
+                Ok(Some(metadata)) => metadata.expires_at,
+                result => {
+                    if let Err(e) = result {
+                        warn!("Replacing unreadable metadata for {name}: {e:?}");
+                    }
+                    let file_metadata = entry.metadata().await?;
+                    let modified = file_metadata.modified()?;
+                    let expires_at = modified + default_retention;
+                    let mut upload_metadata = Metadata::new(expires_at);
+                    upload_metadata.uploaded_at = Some(modified);
+                    upload_metadata.size = Some(file_metadata.len());
+                    upload_metadata.mime = Path::new(&name)
+                        .extension()
+                        .and_then(|ext| ext.to_str())
+                        .map(guess_mime);
+                    metadata.write(&name, &upload_metadata).await?;
+                    expires_at
+                }
+            };
+            schedule.insert(name, expires_at);
+        }
+        debug!("loaded {} pending expiries", schedule.deadlines.len());
+
+        Ok(Self {
+            inner: Arc::new(Inner {
+                dir,
+                metadata,
+                schedule: Mutex::new(schedule),
+                notify: Notify::new(),
+            }),
+        })
+    }
+
+    pub fn schedule(&self, name: String, expires_at: SystemTime) {
+        self.inner.schedule.lock().unwrap().insert(name, expires_at);
+        self.inner.notify.notify_one();
+    }
+
+    /// Forgets the deadline of an upload that was deleted early.
+    pub fn unschedule(&self, name: &str) {
+        self.inner.schedule.lock().unwrap().remove(name);
+    }
+
+    /// Deletes overdue uploads, then sleeps until the next deadline or until a new one is scheduled.
+    pub async fn run(self, quota: Quota) {
+        loop {
+            let now = SystemTime::now();
+            let (overdue, next_deadline) = {
+                let mut schedule = self.inner.schedule.lock().unwrap();
+                let overdue = schedule.pop_overdue(now);
+                let next_deadline = schedule.deadlines.first().map(|(at, _)| *at);
+                (overdue, next_deadline)
+            };
+
+            if let Some(name) = overdue {
+                info!("Deleting {name}");
+                if let Err(e) =
+                    remove_upload(&self.inner.dir, &self.inner.metadata, &quota, &name).await
+                {
+                    warn!("Failed to delete file {name}: {e}");
+                }
+                continue;
+            }
+
+            match next_deadline {
+                Some(deadline) => {
+                    let wait = deadline.duration_since(now).unwrap_or_default();
+                    tokio::select! {
+                        _ = tokio::time::sleep(wait) => {}
+                        _ = self.inner.notify.notified() => {}
+                    }
+                }
+                None => self.inner.notify.notified().await,
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_expiry_sweeps_overdue_on_load() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let dir = temp_dir.path().to_path_buf();
+
+    let metadata = MetadataStore::new(dir.clone());
+
+    tokio::fs::write(dir.join("overdue.txt"), "a")
+        .await
+        .unwrap();
+    metadata
+        .write("overdue.txt", &Metadata::new(SystemTime::UNIX_EPOCH))
+        .await
+        .unwrap();
+    tokio::fs::write(dir.join("legacy.txt"), "b").await.unwrap();
+
+    let expiry = Expiry::load(dir.clone(), metadata.clone(), Duration::from_secs(60))
+        .await
+        .unwrap();
+    let quota = Quota::load(dir.clone(), metadata.clone(), expiry.clone(), None, false)
+        .await
+        .unwrap();
+    let task = tokio::spawn(expiry.run(quota));
+
+    while dir.join("overdue.txt").exists() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    task.abort();
+
+    assert!(metadata.read("overdue.txt").await.unwrap().is_none());
+    let legacy = metadata.read("legacy.txt").await.unwrap().unwrap();
+    assert!(legacy.expires_at > SystemTime::now());
+    assert_eq!(legacy.size, Some(1));
+    assert_eq!(legacy.mime.as_deref(), Some("text/plain"));
+}
@@ -0,0 +1,326 @@
+use std::{
+    collections::HashMap,
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Weak},
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+use tokio::sync::{Mutex, OwnedMutexGuard};
+use tracing::{debug, warn};
+
+use crate::{server::compression::Encoding, unix_time};
+
+/// Sidecar files live in a hidden directory next to the uploads they describe.
+pub const METADATA_DIR: &str = ".meta";
+
+/// Everything known about an upload besides its contents.
+///
+/// Fields added after the first release are optional, since older sidecars lack them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Metadata {
+    #[serde(
+        default,
+        with = "unix_time::option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub uploaded_at: Option<SystemTime>,
+    #[serde(with = "unix_time")]
+    pub expires_at: SystemTime,
+    /// The upload is deleted once it has been downloaded in full this many times.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_downloads: Option<u32>,
+    /// Counts of uploads without `max_downloads` aren't synced, so they may fall behind
+    /// after a crash.
+    #[serde(default)]
+    pub downloads: u32,
+    /// SHA-256 of the secret that lets the uploader delete the file early.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delete_token_hash: Option<String>,
+    /// Label of the token the upload was made with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uploaded_by: Option<String>,
+    /// Name of the file on the uploader's machine.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// SHA-256 of the contents in hex.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mime: Option<String>,
+    /// How the stored contents are compressed, plain if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<Encoding>,
+    /// ID of the key the stored contents are encrypted with, plain if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encrypted_with: Option<String>,
+    /// Set while the stored contents are being swapped for new ones.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replacing: Option<Replacement>,
+}
+
+/// How the contents an upload is getting are stored.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Replacement {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encrypted_with: Option<String>,
+}
+
+impl Metadata {
+    pub fn new(expires_at: SystemTime) -> Self {
+        Self {
+            uploaded_at: None,
+            expires_at,
+            max_downloads: None,
+            downloads: 0,
+            delete_token_hash: None,
+            uploaded_by: None,
+            original_name: None,
+            size: None,
+            sha256: None,
+            mime: None,
+            encoding: None,
+            encrypted_with: None,
+            replacing: None,
+        }
+    }
+
+    pub fn downloads_exhausted(&self) -> bool {
+        self.max_downloads.is_some_and(|max| self.downloads >= max)
+    }
+}
+
+/// The MIME type uploads with extension `ext` are served as.
+pub fn guess_mime(ext: &str) -> String {
+    mime_guess::from_ext(ext)
+        .first_or_octet_stream()
+        .essence_str()
+        .to_string()
+}
+
+/// Reads and writes the sidecar files of every upload under a storage directory.
+#[derive(Clone)]
+pub struct MetadataStore {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    dir: PathBuf,
+    /// Serializes updates per upload, so updates of different uploads don't wait on
+    /// each other.
+    locks: std::sync::Mutex<HashMap<String, Weak<Mutex<()>>>>,
+}
+
+impl MetadataStore {
+    /// Opens the sidecars under `dir`, dropping what a crash left behind: temp files of
+    /// unfinished writes and sidecars whose upload never made it into place.
+    pub async fn load(dir: PathBuf) -> Result<Self> {
+        let metadata_dir = dir.join(METADATA_DIR);
+        match tokio::fs::read_dir(&metadata_dir).await {
+            Ok(mut entries) => {
+                while let Some(entry) = entries.next_entry().await? {
+                    let file_name = entry.file_name();
+                    let upload_exists =
+                        match file_name.to_str().and_then(|s| s.strip_suffix(".json")) {
+                            Some(name) => tokio::fs::try_exists(dir.join(name)).await?,
+                            None => false,
+                        };
+                    if !upload_exists {
+                        debug!("removing stale metadata {file_name:?}");
+                        if let Err(e) = tokio::fs::remove_file(entry.path()).await {
+                            warn!("Failed to remove stale metadata {file_name:?}: {e}");
+                        }
+                    }
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("failed to read {metadata_dir:?}")),
+        }
+
+        Ok(Self::new(dir))
+    }
+
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                dir,
+                locks: Default::default(),
+            }),
+        }
+    }
+
+    async fn lock(&self, name: &str) -> OwnedMutexGuard<()> {
+        let lock = {
+            let mut locks = self.inner.locks.lock().unwrap();
+            locks.retain(|_, lock| lock.strong_count() > 0);
+            match locks.get(name).and_then(Weak::upgrade) {
+                Some(lock) => lock,
+                None => {
+                    let lock = Arc::new(Mutex::new(()));
+                    locks.insert(name.to_string(), Arc::downgrade(&lock));
+                    lock
+                }
+            }
+        };
+
+        lock.lock_owned().await
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.inner
+            .dir
+            .join(METADATA_DIR)
+            .join(format!("{name}.json"))
+    }
+
+    pub async fn read(&self, name: &str) -> Result<Option<Metadata>> {
+        let path = self.path(name);
+        let contents = match tokio::fs::read(&path).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("failed to read {path:?}")),
+        };
+        let metadata = serde_json::from_slice(&contents)
+            .with_context(|| format!("failed to parse {path:?}"))?;
+
+        Ok(Some(metadata))
+    }
+
+    /// Replaces the sidecar of `name`.
+    pub async fn write(&self, name: &str, metadata: &Metadata) -> Result<()> {
+        self.write_sidecar(name, metadata, true, true).await?;
+
+        Ok(())
+    }
+
+    /// Writes the sidecar of a new upload, returning `false` without touching anything if
+    /// `name` already has one.
+    ///
+    /// Uploads claim their name this way before they are moved into place, so every
+    /// upload that can be downloaded has complete metadata, even after a crash.
+    pub async fn create(&self, name: &str, metadata: &Metadata) -> Result<bool> {
+        self.write_sidecar(name, metadata, false, true).await
+    }
+
+    /// Writes a temporary file first so a crash never leaves a truncated sidecar behind,
+    /// syncing it unless the write is fine to lose.
+    async fn write_sidecar(
+        &self,
+        name: &str,
+        metadata: &Metadata,
+        replace: bool,
+        sync: bool,
+    ) -> Result<bool> {
+        let metadata_dir = self.inner.dir.join(METADATA_DIR);
+        let path = self.path(name);
+        let contents = serde_json::to_vec(metadata)?;
+        tokio::fs::create_dir_all(&metadata_dir).await?;
+
+        tokio::task::spawn_blocking(move || {
+            let mut temp_file = NamedTempFile::new_in(&metadata_dir)?;
+            temp_file.write_all(&contents)?;
+            if sync {
+                temp_file.as_file().sync_all()?;
+            }
+
+            let result = if replace {
+                temp_file.persist(&path).map_err(|e| e.error)
+            } else {
+                temp_file.persist_noclobber(&path).map_err(|e| e.error)
+            };
+            match result {
+                Ok(_) => {}
+                Err(e) if !replace && e.kind() == ErrorKind::AlreadyExists => return Ok(false),
+                Err(e) => return Err(e).with_context(|| format!("failed to write {path:?}")),
+            }
+            if sync {
+                sync_dir(&metadata_dir)?;
+            }
+
+            Ok(true)
+        })
+        .await?
+    }
+
+    /// Applies `f` to the stored metadata of `name`, serialized with other updates of
+    /// `name` so concurrent requests can't lose each other's changes.
+    ///
+    /// Returns `None` if `name` has no metadata.
+    pub async fn update<T, F>(&self, name: &str, f: F) -> Result<Option<T>>
+    where
+        F: FnOnce(&mut Metadata) -> T,
+    {
+        self.update_with(name, f, true).await
+    }
+
+    /// Like [`update`](Self::update), but doesn't sync the sidecar, for frequent changes
+    /// that are fine to lose in a crash.
+    pub async fn update_unsynced<T, F>(&self, name: &str, f: F) -> Result<Option<T>>
+    where
+        F: FnOnce(&mut Metadata) -> T,
+    {
+        self.update_with(name, f, false).await
+    }
+
+    async fn update_with<T, F>(&self, name: &str, f: F, sync: bool) -> Result<Option<T>>
+    where
+        F: FnOnce(&mut Metadata) -> T,
+    {
+        let _guard = self.lock(name).await;
+
+        let Some(mut metadata) = self.read(name).await? else {
+            return Ok(None);
+        };
+        let ret = f(&mut metadata);
+        self.write_sidecar(name, &metadata, true, sync).await?;
+
+        Ok(Some(ret))
+    }
+
+    pub async fn remove(&self, name: &str) -> Result<()> {
+        // an update in progress would write the sidecar back otherwise
+        let _guard = self.lock(name).await;
+
+        match tokio::fs::remove_file(self.path(name)).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Makes renames and new files in `dir` survive a crash.
+pub fn sync_dir(dir: &Path) -> Result<()> {
+    // directories can't be opened as files everywhere, and don't need syncing outside unix
+    #[cfg(unix)]
+    std::fs::File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_drops_stale_sidecars() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let dir = temp_dir.path().to_path_buf();
+
+    let metadata = MetadataStore::new(dir.clone());
+    let upload_metadata = Metadata::new(SystemTime::now());
+    tokio::fs::write(dir.join("kept.txt"), "a").await.unwrap();
+    assert!(metadata.create("kept.txt", &upload_metadata).await.unwrap());
+    assert!(!metadata.create("kept.txt", &upload_metadata).await.unwrap());
+    // claimed, but the upload never made it into place
+    assert!(metadata.create("lost.txt", &upload_metadata).await.unwrap());
+    let unfinished = dir.join(METADATA_DIR).join(".tmpabc");
+    tokio::fs::write(&unfinished, "{").await.unwrap();
+
+    let metadata = MetadataStore::load(dir.clone()).await.unwrap();
+    assert!(metadata.read("kept.txt").await.unwrap().is_some());
+    assert!(metadata.read("lost.txt").await.unwrap().is_none());
+    assert!(!unfinished.exists());
+}
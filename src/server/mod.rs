@@ -1,108 +1,381 @@
+mod auth;
+mod compression;
+mod config;
+mod contents;
+mod encryption;
+mod expiry;
+mod metadata;
 mod naming;
+mod options;
 mod postprocessing;
+mod quota;
+mod resumable;
 mod routes;
+mod state;
+mod storage;
+mod tus;
 mod utils;
 
-use futures::future::{self, BoxFuture};
-use tracing::{debug, info};
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use futures::{
+    FutureExt,
+    future::{self, BoxFuture},
+};
+use tokio::{net::TcpListener, task::JoinHandle};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
 
+pub use crate::server::{
+    auth::TokenPolicy,
+    config::{Config, Retention},
+    naming::StrategyKind,
+    postprocessing::Postprocessors,
+    storage::Storage,
+};
 use crate::server::{
-    naming::init_combinations,
+    auth::Tokens,
+    encryption::run_key_rotation,
+    expiry::Expiry,
+    metadata::MetadataStore,
+    naming::Naming,
+    postprocessing::Templates,
+    quota::Quota,
+    resumable::Sessions,
     routes::get_routes,
-    utils::{cleanup_temp_dir, get_temp_dir_path},
+    state::{Settings, State},
 };
 
-pub async fn run_server<F>(port: u16, upload_token: String, stop_signal: F)
-where
-    F: Future<Output = &'static str> + Send + 'static,
-{
-    let temp_dir = get_temp_dir_path().await;
-    debug!("{}", temp_dir.display());
+type LoadConfig = Box<dyn Fn() -> Result<Config> + Send + Sync>;
 
-    tokio::task::spawn_blocking(|| {
-        init_combinations();
-    });
+/// Configures a [`Server`] before it starts listening.
+pub struct ServerBuilder {
+    config: Config,
+    storage: Option<Storage>,
+    handle_signals: bool,
+    load_config: Option<LoadConfig>,
+}
 
-    debug!("Starting server on 0.0.0.0:{port}");
-    warp::serve(get_routes(temp_dir, upload_token))
-        .bind(([0, 0, 0, 0], port))
-        .await
-        .graceful(async move {
-            info!("Listening on 0.0.0.0:{port}");
+impl ServerBuilder {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            storage: None,
+            handle_signals: false,
+            load_config: None,
+        }
+    }
 
-            let mut futures: Vec<BoxFuture<'static, &'static str>> = Vec::new();
+    /// Where uploads are kept, a new ephemeral directory by default.
+    pub fn storage(mut self, storage: Storage) -> Self {
+        self.storage = Some(storage);
+        self
+    }
 
-            #[cfg(unix)]
-            {
-                use tokio::signal::unix::{SignalKind, signal};
-
-                futures.push(Box::pin(async {
-                    let mut interrupt = signal(SignalKind::interrupt()).unwrap();
-                    interrupt.recv().await;
-                    "interrupt"
-                }));
-                futures.push(Box::pin(async {
-                    let mut terminate = signal(SignalKind::terminate()).unwrap();
-                    terminate.recv().await;
-                    "terminate"
-                }));
-                futures.push(Box::pin(async {
-                    let mut hangup = signal(SignalKind::hangup()).unwrap();
-                    hangup.recv().await;
-                    "hangup"
-                }));
+    /// Shut down on SIGINT and SIGTERM, or Ctrl-C outside of unix, and reload on SIGHUP.
+    ///
+    /// Off by default, since an embedding process usually has its own signal handling.
+    pub fn handle_signals(mut self, handle_signals: bool) -> Self {
+        self.handle_signals = handle_signals;
+        self
+    }
+
+    /// How to get a new config when reloading on SIGHUP, where a config that fails to
+    /// load leaves the previous one in place.
+    pub fn reload_with<L>(mut self, load_config: L) -> Self
+    where
+        L: Fn() -> Result<Config> + Send + Sync + 'static,
+    {
+        self.load_config = Some(Box::new(load_config));
+        self
+    }
+
+    /// Loads the state kept in the storage directory and starts listening on every
+    /// bind address, which may use port 0 to get an ephemeral port.
+    pub async fn start(self) -> Result<Server> {
+        let Self {
+            config,
+            storage,
+            handle_signals,
+            load_config,
+        } = self;
+        config.validate()?;
+        let storage = match storage {
+            Some(storage) => storage,
+            None => Storage::ephemeral()?,
+        };
+
+        let dir = storage.path().to_path_buf();
+        match storage {
+            Storage::Persistent(_) => info!("Storing uploads in {}", dir.display()),
+            Storage::Ephemeral(_) => info!(
+                "Storing uploads in ephemeral directory {}, they will be deleted on shutdown",
+                dir.display()
+            ),
+        }
+
+        let tokens = Tokens::load(&config).await?;
+        let metadata = MetadataStore::load(dir.clone()).await?;
+        let expiry = Expiry::load(dir.clone(), metadata.clone(), config.retention.default).await?;
+        let quota = Quota::load(
+            dir.clone(),
+            metadata.clone(),
+            expiry.clone(),
+            config.storage_quota,
+            config.evict_on_quota,
+        )
+        .await?;
+        let naming = Naming::load(dir.clone(), &config.word_lists).await?;
+        let sessions = Sessions::load(dir.clone(), &quota, &tokens).await?;
+        let templates = Templates::load(config.templates_dir.as_deref()).await?;
+
+        let mut listeners = Vec::with_capacity(config.bind.len());
+        let mut local_addrs = Vec::with_capacity(config.bind.len());
+        for addr in &config.bind {
+            debug!("Binding {addr}");
+            let listener = TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("failed to bind {addr}"))?;
+            let local_addr = listener.local_addr()?;
+            info!("Listening on {local_addr}");
+            local_addrs.push(local_addr);
+            listeners.push(listener);
+        }
+
+        let state = State::new(
+            dir.clone(),
+            metadata,
+            expiry.clone(),
+            quota.clone(),
+            sessions,
+            Settings {
+                config,
+                tokens,
+                naming,
+                templates,
+            },
+        );
+        let shutdown = ShutdownHandle {
+            token: CancellationToken::new(),
+        };
+
+        let expiry_task = tokio::spawn(expiry.run(quota));
+        let key_rotation_task = tokio::spawn(run_key_rotation(state.clone()));
+        let sessions = state.sessions.clone();
+        let session_reaper_task = tokio::spawn(sessions.clone().run_reaper());
+        let reload_task = match load_config {
+            Some(load_config) if handle_signals => {
+                Some(tokio::spawn(reload_on_hangup(state.clone(), load_config)))
             }
+            _ => None,
+        };
 
-            if cfg!(not(unix)) {
-                use tokio::signal::ctrl_c;
+        let stop_signal = wait_for_shutdown(shutdown.clone(), handle_signals)
+            .boxed()
+            .shared();
+        let routes = get_routes(state.clone());
+        let mut servers = Vec::with_capacity(listeners.len());
+        for listener in listeners {
+            let server = warp::serve(routes.clone())
+                .incoming(listener)
+                .graceful(stop_signal.clone());
+            servers.push(tokio::spawn(server.run()));
+        }
 
-                futures.push(Box::pin(async {
-                    ctrl_c().await.unwrap();
-                    "Ctrl-C"
-                }));
+        let task = tokio::spawn(async move {
+            let mut result = Ok(());
+            for server in servers {
+                if let Err(e) = server.await {
+                    result = Err(e.into());
+                }
             }
 
-            futures.push(Box::pin(stop_signal));
+            if let Some(reload_task) = reload_task {
+                reload_task.abort();
+            }
+            expiry_task.abort();
+            key_rotation_task.abort();
+            session_reaper_task.abort();
+            sessions.keep().await;
+            storage.close();
 
-            let (reason, ..) = future::select_all(futures).await;
-            info!("Shutting down due to {reason}");
+            result
+        });
 
-            cleanup_temp_dir().await;
+        Ok(Server {
+            local_addrs,
+            dir,
+            state,
+            shutdown,
+            task,
         })
-        .run()
-        .await;
+    }
 }
 
-#[tokio::test]
-async fn test_run_server() {
-    let dir = get_temp_dir_path().await;
+/// A running server, stopped through [`Server::shutdown`] or a [`ShutdownHandle`].
+pub struct Server {
+    local_addrs: Vec<SocketAddr>,
+    dir: PathBuf,
+    state: State,
+    shutdown: ShutdownHandle,
+    task: JoinHandle<Result<()>>,
+}
 
-    let (stop_signal_tx, stop_signal_rx) = tokio::sync::oneshot::channel();
-    let server_task = tokio::task::spawn(async move {
-        let stop_signal = async move {
-            stop_signal_rx.await.unwrap();
-            "signal"
-        };
-        run_server(8080, "test".to_string(), stop_signal).await;
-    });
+impl Server {
+    pub fn builder(config: Config) -> ServerBuilder {
+        ServerBuilder::new(config)
+    }
 
-    let file_name_1 = {
-        unsafe {
-            std::env::set_var("UPLOAD_TOKEN", "test");
-            std::env::set_var("URL", "http://localhost:8080/");
+    /// The address of the first bind address, with the actual port if it asked for port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addrs[0]
+    }
+
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    /// The directory uploads are stored in.
+    pub fn storage_dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Swaps in a new config, keeping the current one if anything it refers to fails to load.
+    pub async fn reload(&self, config: Config) -> Result<()> {
+        self.state.reload(config).await
+    }
+
+    /// Waits for the server to stop after a shutdown was requested, letting open
+    /// connections finish.
+    pub async fn wait(self) -> Result<()> {
+        self.task.await?
+    }
+
+    pub async fn shutdown(self) -> Result<()> {
+        self.shutdown.shutdown();
+        self.wait().await
+    }
+}
+
+/// Stops a [`Server`] from anywhere, for example another task.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    token: CancellationToken,
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.token.cancel();
+    }
+}
+
+async fn wait_for_shutdown(shutdown: ShutdownHandle, handle_signals: bool) {
+    let mut futures: Vec<BoxFuture<'static, &'static str>> = Vec::new();
+
+    if handle_signals {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{SignalKind, signal};
+
+            futures.push(Box::pin(async {
+                let mut interrupt = signal(SignalKind::interrupt()).unwrap();
+                interrupt.recv().await;
+                "interrupt"
+            }));
+            futures.push(Box::pin(async {
+                let mut terminate = signal(SignalKind::terminate()).unwrap();
+                terminate.recv().await;
+                "terminate"
+            }));
         }
 
-        http_file_uploader::upload("test1".into(), "txt")
-            .await
-            .unwrap();
+        if cfg!(not(unix)) {
+            use tokio::signal::ctrl_c;
 
-        let entry = tokio::fs::read_dir(&dir)
-            .await
-            .unwrap()
-            .next_entry()
+            futures.push(Box::pin(async {
+                ctrl_c().await.unwrap();
+                "Ctrl-C"
+            }));
+        }
+    }
+
+    futures.push(Box::pin(async move {
+        shutdown.token.cancelled().await;
+        "shutdown request"
+    }));
+
+    let (reason, ..) = future::select_all(futures).await;
+    info!("Shutting down due to {reason}");
+}
+
+async fn reload_on_hangup(state: State, load_config: LoadConfig) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let mut hangup = signal(SignalKind::hangup()).unwrap();
+        while hangup.recv().await.is_some() {
+            info!("Reloading configuration due to hangup");
+            let result = match load_config() {
+                Ok(config) => state.reload(config).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                warn!("Keeping the previous configuration, reload failed: {e:#}");
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = (state, load_config);
+    }
+}
+
+#[tokio::test]
+async fn test_run_server() {
+    let config = Config {
+        bind: vec![([127, 0, 0, 1], 0).into()],
+        upload_token: Some("test".to_string()),
+        ..Default::default()
+    };
+    let server = Server::builder(config).start().await.unwrap();
+    let dir = server.storage_dir().to_path_buf();
+    let url = format!("http://{}", server.local_addr());
+
+    let file_name_1 = {
+        let client = crate::UploadClient::new(&url, "test");
+        let result = client
+            .upload("test1".into(), "txt", &Default::default())
             .await
-            .unwrap()
             .unwrap();
+        assert_eq!(result.url, format!("{url}/{}", result.name));
+        assert!(result.expires_at.unwrap() > std::time::SystemTime::now());
+        assert!(result.delete_token.is_some());
+        assert_eq!(result.size, Some(5));
+        assert_eq!(result.mime.as_deref(), Some("text/plain"));
+        assert_eq!(
+            result.sha256.unwrap(),
+            utils::to_hex(&<sha2::Sha256 as sha2::Digest>::digest(b"test1"))
+        );
+
+        let mut entries = tokio::fs::read_dir(&dir).await.unwrap();
+        let entry = loop {
+            let entry = entries.next_entry().await.unwrap().unwrap();
+            if entry.file_type().await.unwrap().is_file()
+                && !entry.file_name().to_string_lossy().starts_with('.')
+            {
+                break entry;
+            }
+        };
         let contents = tokio::fs::read_to_string(entry.path()).await.unwrap();
         assert_eq!(contents, "test1");
         entry.file_name().to_string_lossy().to_string()
@@ -111,7 +384,7 @@ async fn test_run_server() {
     let file_name_2 = {
         let client = reqwest::Client::new();
         let res = client
-            .post("http://localhost:8080/upload.txt")
+            .post(format!("{url}/upload.txt"))
             .header("Authorization", "Bearer test")
             .body("test2")
             .send()
@@ -120,11 +393,31 @@ async fn test_run_server() {
         let path = res.text().await.unwrap();
         let contents = tokio::fs::read(dir.join(&path)).await.unwrap();
         assert_eq!(contents, b"test2");
+
+        let res = client
+            .post(format!("{url}/upload.png"))
+            .header("Authorization", "Bearer test")
+            .header("Accept", "text/html, application/json;q=0.9")
+            .header("X-Forwarded-Proto", "https")
+            .header("X-Forwarded-Host", "files.example.com")
+            .body("not really a png")
+            .send()
+            .await
+            .unwrap();
+        let info: serde_json::Value = serde_json::from_slice(&res.bytes().await.unwrap()).unwrap();
+        let name = info["name"].as_str().unwrap();
+        assert_eq!(info["url"], format!("https://files.example.com/{name}"));
+        assert_eq!(info["delete_url"], info["url"]);
+        assert_eq!(info["size"], 16);
+        assert_eq!(info["mime"], "image/png");
+        assert!(info["expires_at"].as_u64().is_some());
+        assert!(info["delete_token"].is_string());
+
         path
     };
 
     {
-        let contents = reqwest::get(format!("http://localhost:8080/{file_name_1}"))
+        let contents = reqwest::get(format!("{url}/{file_name_1}"))
             .await
             .unwrap()
             .text()
@@ -134,7 +427,7 @@ async fn test_run_server() {
     }
 
     {
-        let contents = reqwest::get(format!("http://localhost:8080/{file_name_2}"))
+        let contents = reqwest::get(format!("{url}/{file_name_2}"))
             .await
             .unwrap()
             .text()
@@ -143,8 +436,715 @@ async fn test_run_server() {
         assert_eq!(contents, "test2");
     }
 
-    stop_signal_tx.send(()).unwrap();
-    server_task.await.unwrap();
+    server.shutdown().await.unwrap();
 
     assert!(!dir.exists());
 }
+
+#[tokio::test]
+async fn test_upload_options() {
+    let config = Config {
+        bind: vec![([127, 0, 0, 1], 0).into()],
+        upload_token: Some("test".to_string()),
+        max_upload_size: Some(1024),
+        ..Default::default()
+    };
+    let server = Server::builder(config).start().await.unwrap();
+    let url = format!("http://{}", server.local_addr());
+
+    let client = reqwest::Client::new();
+    let res = client
+        .post(format!("{url}/upload.txt?expires=soon"))
+        .header("Authorization", "Bearer test")
+        .body("test3")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::BAD_REQUEST);
+
+    let res = client
+        .post(format!("{url}/upload.txt"))
+        .header("Authorization", "Bearer test")
+        .body(vec![b'a'; 2048])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::PAYLOAD_TOO_LARGE);
+
+    let file_name = client
+        .post(format!("{url}/upload.txt?naming=hash:8"))
+        .header("Authorization", "Bearer test")
+        .body("hashed")
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    let hash = utils::to_hex(&<sha2::Sha256 as sha2::Digest>::digest(b"hashed"));
+    assert_eq!(file_name, format!("{}.txt", &hash[..8]));
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_delete_with_token() {
+    let config = Config {
+        bind: vec![([127, 0, 0, 1], 0).into()],
+        upload_token: Some("test".to_string()),
+        ..Default::default()
+    };
+    let server = Server::builder(config).start().await.unwrap();
+    let dir = server.storage_dir().to_path_buf();
+    let url = format!("http://{}", server.local_addr());
+
+    let client = reqwest::Client::new();
+    let res = client
+        .post(format!("{url}/upload.txt"))
+        .header("Authorization", "Bearer test")
+        .body("oops")
+        .send()
+        .await
+        .unwrap();
+    let delete_token = res.headers()["delete-token"].to_str().unwrap().to_string();
+    let file_name = res.text().await.unwrap();
+
+    let res = client
+        .delete(format!("{url}/{file_name}"))
+        .header("Authorization", "Bearer wrong")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::NOT_FOUND);
+
+    let res = client
+        .delete(format!("{url}/{file_name}"))
+        .header("Authorization", format!("Bearer {delete_token}"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::NO_CONTENT);
+    assert!(!dir.join(&file_name).exists());
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_vanity_names() {
+    let config = Config {
+        bind: vec![([127, 0, 0, 1], 0).into()],
+        upload_token: Some("test".to_string()),
+        ..Default::default()
+    };
+    let server = Server::builder(config).start().await.unwrap();
+    let url = format!("http://{}", server.local_addr());
+
+    let client = reqwest::Client::new();
+    let upload = |name: &'static str, on_conflict: &'static str| {
+        client
+            .post(format!(
+                "{url}/upload.md?name={name}&on_conflict={on_conflict}"
+            ))
+            .header("Authorization", "Bearer test")
+            .body("notes")
+            .send()
+    };
+
+    let res = upload("release-notes", "reject").await.unwrap();
+    assert_eq!(res.text().await.unwrap(), "release-notes.md");
+
+    let res = upload("release-notes", "reject").await.unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::CONFLICT);
+
+    let res = upload("release-notes", "suffix").await.unwrap();
+    assert_eq!(res.text().await.unwrap(), "release-notes-2.md");
+
+    let res = upload("..%2Fescape", "reject").await.unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::BAD_REQUEST);
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_resumable_client() {
+    let config = Config {
+        bind: vec![([127, 0, 0, 1], 0).into()],
+        upload_token: Some("test".to_string()),
+        ..Default::default()
+    };
+    let server = Server::builder(config).start().await.unwrap();
+    let dir = server.storage_dir().to_path_buf();
+    let url = format!("http://{}", server.local_addr());
+
+    let client = crate::UploadClient::new(&url, "test").with_chunk_size(4);
+    let result = client
+        .upload("resumed in chunks".into(), "txt", &Default::default())
+        .await
+        .unwrap();
+    let contents = tokio::fs::read_to_string(dir.join(&result.name))
+        .await
+        .unwrap();
+    assert_eq!(contents, "resumed in chunks");
+    assert!(result.name.ends_with(".txt"));
+    assert_eq!(result.size, Some(17));
+    assert_eq!(result.mime.as_deref(), Some("text/plain"));
+    assert!(result.sha256.is_some());
+    assert!(result.delete_token.is_some());
+
+    let client = reqwest::Client::new();
+    let res = client
+        .post(format!("{url}/files"))
+        .header("Authorization", "Bearer test")
+        .header("Tus-Resumable", "1.0.0")
+        .header("Upload-Length", 8)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::CREATED);
+    let location = res.headers()["location"].to_str().unwrap().to_string();
+
+    let res = client
+        .patch(format!("{url}{location}"))
+        .header("Authorization", "Bearer test")
+        .header("Tus-Resumable", "1.0.0")
+        .header("Content-Type", "application/offset+octet-stream")
+        .header("Upload-Offset", 4)
+        .body("late")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::CONFLICT);
+    assert_eq!(res.headers()["upload-offset"], "0");
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_tus_uploads() {
+    let config = Config {
+        bind: vec![([127, 0, 0, 1], 0).into()],
+        upload_token: Some("test".to_string()),
+        max_upload_size: Some(1024),
+        ..Default::default()
+    };
+    let server = Server::builder(config).start().await.unwrap();
+    let dir = server.storage_dir().to_path_buf();
+    let url = format!("http://{}", server.local_addr());
+
+    let client = reqwest::Client::new();
+    let res = client
+        .request(reqwest::Method::OPTIONS, format!("{url}/files"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.headers()["tus-version"], "1.0.0");
+    assert_eq!(res.headers()["tus-max-size"], "1024");
+
+    let res = client
+        .post(format!("{url}/files"))
+        .header("Authorization", "Bearer test")
+        .header("Upload-Length", 9)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::PRECONDITION_FAILED);
+
+    let res = client
+        .post(format!("{url}/files"))
+        .header("Authorization", "Bearer test")
+        .header("Tus-Resumable", "1.0.0")
+        .header("Upload-Length", 9)
+        .header("Upload-Metadata", "filename bm90ZXMubWQ=")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::CREATED);
+    let location = res.headers()["location"].to_str().unwrap().to_string();
+
+    let append = |offset: u64, chunk: &'static str| {
+        client
+            .patch(format!("{url}{location}"))
+            .header("Authorization", "Bearer test")
+            .header("Tus-Resumable", "1.0.0")
+            .header("Content-Type", "application/offset+octet-stream")
+            .header("Upload-Offset", offset)
+            .body(chunk)
+            .send()
+    };
+    let res = append(0, "tus ").await.unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::NO_CONTENT);
+    assert_eq!(res.headers()["upload-offset"], "4");
+
+    let res = client
+        .head(format!("{url}{location}"))
+        .header("Authorization", "Bearer test")
+        .header("Tus-Resumable", "1.0.0")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.headers()["upload-offset"], "4");
+    assert_eq!(res.headers()["upload-length"], "9");
+
+    let res = append(4, "notes").await.unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::NO_CONTENT);
+    let name = res.headers()["upload-name"].to_str().unwrap().to_string();
+    assert!(name.ends_with(".md"));
+    assert!(res.headers().contains_key("delete-token"));
+    let contents = tokio::fs::read_to_string(dir.join(&name)).await.unwrap();
+    assert_eq!(contents, "tus notes");
+
+    let metadata = server.state.metadata.read(&name).await.unwrap().unwrap();
+    assert_eq!(metadata.original_name.as_deref(), Some("notes.md"));
+    assert_eq!(metadata.uploaded_by.as_deref(), Some("default"));
+    assert_eq!(metadata.size, Some(9));
+    assert_eq!(metadata.mime.as_deref(), Some("text/markdown"));
+    assert!(metadata.uploaded_at.is_some());
+    assert!(metadata.sha256.is_some());
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_start_rejects_invalid_config() {
+    let config = Config {
+        upload_token: Some("test".to_string()),
+        bind: Vec::new(),
+        ..Default::default()
+    };
+    assert!(Server::builder(config).start().await.is_err());
+
+    let mut config = Config {
+        bind: vec![([127, 0, 0, 1], 0).into()],
+        upload_token: Some("test".to_string()),
+        ..Default::default()
+    };
+    config.retention.default = config.retention.max + std::time::Duration::from_secs(1);
+    assert!(Server::builder(config).start().await.is_err());
+}
+
+#[tokio::test]
+async fn test_resumable_uploads_survive_restart() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let config = || Config {
+        bind: vec![([127, 0, 0, 1], 0).into()],
+        upload_token: Some("test".to_string()),
+        ..Default::default()
+    };
+    let start = || async {
+        Server::builder(config())
+            .storage(Storage::persistent(temp_dir.path()).await.unwrap())
+            .start()
+            .await
+            .unwrap()
+    };
+    let client = reqwest::Client::new();
+
+    let server = start().await;
+    let url = format!("http://{}", server.local_addr());
+    let res = client
+        .post(format!("{url}/files"))
+        .header("Authorization", "Bearer test")
+        .header("Tus-Resumable", "1.0.0")
+        .header("Upload-Length", 8)
+        .header("Upload-Metadata", "ext dHh0")
+        .send()
+        .await
+        .unwrap();
+    let location = res.headers()["location"].to_str().unwrap().to_string();
+    let append = |url: &str, offset: u64, chunk: &'static str| {
+        client
+            .patch(format!("{url}{location}"))
+            .header("Authorization", "Bearer test")
+            .header("Tus-Resumable", "1.0.0")
+            .header("Content-Type", "application/offset+octet-stream")
+            .header("Upload-Offset", offset)
+            .body(chunk)
+            .send()
+    };
+    let res = append(&url, 0, "half").await.unwrap();
+    assert_eq!(res.headers()["upload-offset"], "4");
+    server.shutdown().await.unwrap();
+
+    let server = start().await;
+    let url = format!("http://{}", server.local_addr());
+    let res = client
+        .head(format!("{url}{location}"))
+        .header("Authorization", "Bearer test")
+        .header("Tus-Resumable", "1.0.0")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.headers()["upload-offset"], "4");
+    let res = append(&url, 4, "done").await.unwrap();
+    let name = res.headers()["upload-name"].to_str().unwrap().to_string();
+    assert!(name.ends_with(".txt"));
+    let contents = tokio::fs::read_to_string(temp_dir.path().join(&name))
+        .await
+        .unwrap();
+    assert_eq!(contents, "halfdone");
+    let session_dir = temp_dir.path().join(resumable::SESSION_DIR);
+    assert_eq!(std::fs::read_dir(session_dir).unwrap().count(), 0);
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_tus_busy_session() {
+    let config = Config {
+        bind: vec![([127, 0, 0, 1], 0).into()],
+        upload_token: Some("test".to_string()),
+        ..Default::default()
+    };
+    let server = Server::builder(config).start().await.unwrap();
+    let url = format!("http://{}", server.local_addr());
+    let client = reqwest::Client::new();
+
+    let res = client
+        .post(format!("{url}/files"))
+        .header("Authorization", "Bearer test")
+        .header("Tus-Resumable", "1.0.0")
+        .header("Upload-Length", 10)
+        .send()
+        .await
+        .unwrap();
+    let location = res.headers()["location"].to_str().unwrap().to_string();
+
+    // an append that keeps its session while the client is still sending
+    let (sender, receiver) = tokio::sync::mpsc::channel::<Result<&'static str, std::io::Error>>(1);
+    sender.send(Ok("busy")).await.unwrap();
+    let append = tokio::spawn(
+        client
+            .patch(format!("{url}{location}"))
+            .header("Authorization", "Bearer test")
+            .header("Tus-Resumable", "1.0.0")
+            .header("Content-Type", "application/offset+octet-stream")
+            .header("Upload-Offset", 0)
+            .body(reqwest::Body::wrap_stream(
+                tokio_stream::wrappers::ReceiverStream::new(receiver),
+            ))
+            .send(),
+    );
+
+    let head = || {
+        client
+            .head(format!("{url}{location}"))
+            .header("Authorization", "Bearer test")
+            .header("Tus-Resumable", "1.0.0")
+            .send()
+    };
+    let mut status = head().await.unwrap().status();
+    for _ in 0..100 {
+        if status == reqwest::StatusCode::LOCKED {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        status = head().await.unwrap().status();
+    }
+    assert_eq!(status, reqwest::StatusCode::LOCKED);
+
+    drop(sender);
+    let res = append.await.unwrap().unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::NO_CONTENT);
+    let res = head().await.unwrap();
+    assert_eq!(res.headers()["upload-offset"], "4");
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_download_counts() {
+    let config = Config {
+        bind: vec![([127, 0, 0, 1], 0).into()],
+        upload_token: Some("test".to_string()),
+        ..Default::default()
+    };
+    let server = Server::builder(config).start().await.unwrap();
+    let dir = server.storage_dir().to_path_buf();
+    let url = format!("http://{}", server.local_addr());
+    let client = reqwest::Client::new();
+    let upload = |query: &'static str, body: &'static str| {
+        client
+            .post(format!("{url}/upload.txt{query}"))
+            .header("Authorization", "Bearer test")
+            .body(body)
+            .send()
+    };
+    let downloads = |name: String| {
+        let metadata = server.state.metadata.clone();
+        async move { metadata.read(&name).await.unwrap().unwrap().downloads }
+    };
+    let wait_for_downloads = |name: String, expected: u32| async move {
+        while downloads(name.clone()).await != expected {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+    };
+
+    let res = client
+        .post(format!("{url}/upload.txt"))
+        .header("Authorization", "Bearer test")
+        .header("Max-Downloads", 0)
+        .body("never")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::BAD_REQUEST);
+
+    let name = upload("", "unlimited").await.unwrap().text().await.unwrap();
+    for _ in 0..2 {
+        let res = client.get(format!("{url}/{name}")).send().await.unwrap();
+        assert_eq!(res.text().await.unwrap(), "unlimited");
+    }
+    wait_for_downloads(name.clone(), 2).await;
+
+    // neither ranges nor revalidations are downloads
+    let res = client
+        .get(format!("{url}/{name}"))
+        .header("Range", "bytes=0-1")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::PARTIAL_CONTENT);
+    let last_modified = res.headers()["last-modified"].clone();
+    assert_eq!(res.text().await.unwrap(), "un");
+    let res = client
+        .get(format!("{url}/{name}"))
+        .header("If-Modified-Since", last_modified)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::NOT_MODIFIED);
+    assert_eq!(downloads(name.clone()).await, 2);
+
+    // a partial download gives its place under the limit back
+    let name = upload("?downloads=1", "limited")
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    let res = client
+        .get(format!("{url}/{name}"))
+        .header("Range", "bytes=0-1")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::PARTIAL_CONTENT);
+    res.bytes().await.unwrap();
+    wait_for_downloads(name.clone(), 0).await;
+
+    let res = client.get(format!("{url}/{name}")).send().await.unwrap();
+    assert_eq!(res.text().await.unwrap(), "limited");
+    let res = client.get(format!("{url}/{name}")).send().await.unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::NOT_FOUND);
+    while dir.join(&name).exists() {
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_compressed_uploads() {
+    let config = Config {
+        bind: vec![([127, 0, 0, 1], 0).into()],
+        upload_token: Some("test".to_string()),
+        compression: Some(compression::Encoding::Gzip),
+        ..Default::default()
+    };
+    let server = Server::builder(config).start().await.unwrap();
+    let dir = server.storage_dir().to_path_buf();
+    let url = format!("http://{}", server.local_addr());
+    let log = "the same line over and over\n".repeat(100);
+
+    let upload = |ext: &'static str, body: String| {
+        let url = url.clone();
+        async move {
+            reqwest::Client::new()
+                .post(format!("{url}/upload.{ext}"))
+                .header("Authorization", "Bearer test")
+                .body(body)
+                .send()
+                .await
+                .unwrap()
+                .text()
+                .await
+                .unwrap()
+        }
+    };
+    let plain_client = reqwest::Client::builder()
+        .no_gzip()
+        .no_brotli()
+        .no_zstd()
+        .no_deflate()
+        .build()
+        .unwrap();
+
+    let name = upload("log", log.clone()).await;
+    assert!(tokio::fs::metadata(dir.join(&name)).await.unwrap().len() < log.len() as u64);
+    let metadata = server.state.metadata.read(&name).await.unwrap().unwrap();
+    assert_eq!(metadata.encoding, Some(compression::Encoding::Gzip));
+    assert_eq!(metadata.size, Some(log.len() as u64));
+
+    // served as stored to clients that accept gzip
+    let res = plain_client
+        .get(format!("{url}/{name}"))
+        .header("Accept-Encoding", "gzip")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.headers()["content-encoding"], "gzip");
+    let body = res.bytes().await.unwrap();
+    assert_ne!(body, log.as_bytes());
+    assert_eq!(
+        reqwest::get(format!("{url}/{name}"))
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap(),
+        log
+    );
+
+    // decompressed for everyone else
+    let res = plain_client
+        .get(format!("{url}/{name}"))
+        .send()
+        .await
+        .unwrap();
+    assert!(res.headers().get("content-encoding").is_none());
+    assert_eq!(res.text().await.unwrap(), log);
+
+    // postprocessors see the plaintext
+    let name = upload("md", format!("# Build log\n{log}")).await;
+    let contents = plain_client
+        .get(format!("{url}/{name}"))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(contents.contains("<title>Build log</title>"));
+
+    // not worth compressing
+    let name = upload("png", log.clone()).await;
+    let metadata = server.state.metadata.read(&name).await.unwrap().unwrap();
+    assert_eq!(metadata.encoding, None);
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_encrypted_uploads() {
+    use base64::{Engine, prelude::BASE64_STANDARD};
+
+    let key = |byte: u8| -> encryption::EncryptionKey {
+        BASE64_STANDARD.encode([byte; 32]).parse().unwrap()
+    };
+    let config = |current: Option<u8>, old: Vec<u8>| Config {
+        bind: vec![([127, 0, 0, 1], 0).into()],
+        upload_token: Some("test".to_string()),
+        compression: Some(compression::Encoding::Gzip),
+        encryption: encryption::Keyring {
+            current: current.map(key),
+            old: old.into_iter().map(key).collect(),
+        },
+        ..Default::default()
+    };
+    let server = Server::builder(config(Some(1), Vec::new()))
+        .start()
+        .await
+        .unwrap();
+    let dir = server.storage_dir().to_path_buf();
+    let url = format!("http://{}", server.local_addr());
+    let log = "a secret line over and over\n".repeat(100);
+
+    let name = reqwest::Client::new()
+        .post(format!("{url}/upload.log"))
+        .header("Authorization", "Bearer test")
+        .body(log.clone())
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    let stored = tokio::fs::read(dir.join(&name)).await.unwrap();
+    assert!(!stored.windows(6).any(|w| w == b"secret"));
+    let metadata = server.state.metadata.read(&name).await.unwrap().unwrap();
+    assert_eq!(metadata.encrypted_with, Some(key(1).id()));
+
+    let plain_client = reqwest::Client::builder()
+        .no_gzip()
+        .no_brotli()
+        .no_zstd()
+        .no_deflate()
+        .build()
+        .unwrap();
+    let res = plain_client
+        .get(format!("{url}/{name}"))
+        .send()
+        .await
+        .unwrap();
+    assert!(res.headers().get("content-encoding").is_none());
+    assert_eq!(res.text().await.unwrap(), log);
+    let res = reqwest::get(format!("{url}/{name}")).await.unwrap();
+    assert_eq!(res.text().await.unwrap(), log);
+
+    // rotate to a new key, keeping the old one around to decrypt with
+    server.reload(config(Some(2), vec![1])).await.unwrap();
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    loop {
+        let metadata = server.state.metadata.read(&name).await.unwrap().unwrap();
+        if metadata.encrypted_with == Some(key(2).id()) {
+            break;
+        }
+        assert!(std::time::Instant::now() < deadline);
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    assert_ne!(tokio::fs::read(dir.join(&name)).await.unwrap(), stored);
+    assert!(
+        !dir.join(storage::BLOB_DIR)
+            .join(format!("{}.gzip.{}", metadata.sha256.unwrap(), key(1).id()))
+            .exists()
+    );
+
+    // only the new key is needed from now on
+    server.reload(config(Some(2), Vec::new())).await.unwrap();
+    let res = plain_client
+        .get(format!("{url}/{name}"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.text().await.unwrap(), log);
+
+    // uploads are only decrypted if they were encrypted by the server, not because they
+    // look like it
+    let ciphertext = tokio::fs::read(dir.join(&name)).await.unwrap();
+    server.reload(config(None, vec![2])).await.unwrap();
+    let backup = reqwest::Client::new()
+        .post(format!("{url}/upload.bin"))
+        .header("Authorization", "Bearer test")
+        .body(ciphertext.clone())
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    let res = plain_client
+        .get(format!("{url}/{backup}"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.bytes().await.unwrap(), ciphertext);
+    let res = plain_client
+        .get(format!("{url}/{name}"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.text().await.unwrap(), log);
+
+    server.shutdown().await.unwrap();
+}
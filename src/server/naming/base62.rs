@@ -0,0 +1,18 @@
+use rand::{RngExt, distr::Alphanumeric};
+
+use crate::server::naming::{NamingInput, NamingStrategy};
+
+/// Short unguessable names made of random letters and digits.
+pub struct Base62Names {
+    pub length: usize,
+}
+
+impl NamingStrategy for Base62Names {
+    fn propose(&self, _upload: &NamingInput<'_>, _attempt: usize) -> String {
+        rand::rng()
+            .sample_iter(Alphanumeric)
+            .take(self.length)
+            .map(char::from)
+            .collect()
+    }
+}
@@ -0,0 +1,20 @@
+use crate::server::naming::{NamingInput, NamingStrategy};
+
+/// Names taken from the start of the upload's SHA-256, so identical content gets a
+/// predictable name.
+pub struct ContentHashNames {
+    pub length: usize,
+}
+
+impl NamingStrategy for ContentHashNames {
+    fn propose(&self, upload: &NamingInput<'_>, attempt: usize) -> String {
+        // use more of the hash for every name that is already taken
+        let hash = upload.content_hash;
+        let length = self.length + attempt;
+        if length <= hash.len() {
+            hash[..length].to_string()
+        } else {
+            format!("{hash}-{}", length - hash.len())
+        }
+    }
+}
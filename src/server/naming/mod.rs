@@ -0,0 +1,135 @@
+mod base62;
+mod content_hash;
+mod sequential;
+mod vanity;
+mod words;
+
+use std::{path::PathBuf, str::FromStr, sync::Arc};
+
+use anyhow::{Context, Error, Result, bail, ensure};
+use futures::future::{self, BoxFuture};
+use serde::{Deserialize, Serialize};
+
+pub use crate::server::naming::vanity::{VanityName, validate_vanity_name};
+use crate::server::naming::{
+    base62::Base62Names, content_hash::ContentHashNames, sequential::SequentialNames,
+    words::WordNames,
+};
+
+const MIN_LENGTH: usize = 4;
+const MAX_LENGTH: usize = 64;
+
+/// Comes up with names for uploads, without the extension.
+pub trait NamingStrategy: Send + Sync {
+    /// `attempt` counts how many proposals for this upload were already taken.
+    fn propose(&self, upload: &NamingInput<'_>, attempt: usize) -> String;
+
+    /// Persists whatever keeps names from repeating after a restart.
+    fn save(&self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(future::ok(()))
+    }
+}
+
+/// What's known about a finished upload when it gets its name.
+pub struct NamingInput<'a> {
+    /// Hex SHA-256 of the contents.
+    pub content_hash: &'a str,
+}
+
+/// Selects a built-in strategy, written like `words`, `base62:12`, `hash:16` or `sequential`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum StrategyKind {
+    #[default]
+    Words,
+    Base62 {
+        length: usize,
+    },
+    ContentHash {
+        length: usize,
+    },
+    Sequential,
+}
+
+impl FromStr for StrategyKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, length) = match s.split_once(':') {
+            Some((name, length)) => {
+                let length = length
+                    .parse::<usize>()
+                    .with_context(|| format!("invalid length in naming strategy {s:?}"))?;
+                ensure!(
+                    (MIN_LENGTH..=MAX_LENGTH).contains(&length),
+                    "naming strategy length must be between {MIN_LENGTH} and {MAX_LENGTH}"
+                );
+                (name, Some(length))
+            }
+            None => (s, None),
+        };
+
+        Ok(match (name, length) {
+            ("words", None) => Self::Words,
+            ("base62", length) => Self::Base62 {
+                length: length.unwrap_or(8),
+            },
+            ("hash", length) => Self::ContentHash {
+                length: length.unwrap_or(12),
+            },
+            ("sequential", None) => Self::Sequential,
+            _ => bail!("unknown naming strategy {s:?}"),
+        })
+    }
+}
+
+/// The built-in strategies, sharing state between uploads where they keep any.
+#[derive(Clone)]
+pub struct Naming {
+    words: WordNames,
+    sequential: SequentialNames,
+}
+
+impl Naming {
+    pub async fn load(dir: PathBuf, word_lists: &[PathBuf]) -> Result<Self> {
+        Ok(Self {
+            words: WordNames::load(dir.clone(), word_lists).await?,
+            sequential: SequentialNames::load(dir).await?,
+        })
+    }
+
+    /// Loads new word lists, keeping the state of every other strategy.
+    pub async fn reload(&self, dir: PathBuf, word_lists: &[PathBuf]) -> Result<Self> {
+        Ok(Self {
+            words: WordNames::load(dir, word_lists).await?,
+            sequential: self.sequential.clone(),
+        })
+    }
+
+    pub fn strategy(&self, kind: StrategyKind) -> Arc<dyn NamingStrategy> {
+        match kind {
+            StrategyKind::Words => Arc::new(self.words.clone()),
+            StrategyKind::Base62 { length } => Arc::new(Base62Names { length }),
+            StrategyKind::ContentHash { length } => Arc::new(ContentHashNames { length }),
+            StrategyKind::Sequential => Arc::new(self.sequential.clone()),
+        }
+    }
+}
+
+#[test]
+fn test_strategy_kind_from_str() {
+    assert_eq!(
+        "words".parse::<StrategyKind>().unwrap(),
+        StrategyKind::Words
+    );
+    assert_eq!(
+        "base62:6".parse::<StrategyKind>().unwrap(),
+        StrategyKind::Base62 { length: 6 }
+    );
+    assert_eq!(
+        "hash".parse::<StrategyKind>().unwrap(),
+        StrategyKind::ContentHash { length: 12 }
+    );
+    assert!("base62:1".parse::<StrategyKind>().is_err());
+    assert!("words:5".parse::<StrategyKind>().is_err());
+    assert!("uuid".parse::<StrategyKind>().is_err());
+}
@@ -0,0 +1,80 @@
+use std::{
+    io::ErrorKind,
+    path::PathBuf,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use anyhow::{Context, Result};
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+
+use crate::server::naming::{NamingInput, NamingStrategy};
+
+/// Where the counter is kept so numbering continues after a restart.
+const STATE_FILE: &str = ".sequence.json";
+
+/// Names counting up from 1.
+#[derive(Clone)]
+pub struct SequentialNames {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    state_path: PathBuf,
+    next: AtomicU64,
+    save_lock: tokio::sync::Mutex<()>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SequenceState {
+    next: u64,
+}
+
+impl SequentialNames {
+    pub async fn load(dir: PathBuf) -> Result<Self> {
+        let state_path = dir.join(STATE_FILE);
+        let next = match tokio::fs::read(&state_path).await {
+            Ok(contents) => {
+                serde_json::from_slice::<SequenceState>(&contents)
+                    .with_context(|| format!("failed to parse {state_path:?}"))?
+                    .next
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => 1,
+            Err(e) => return Err(e).with_context(|| format!("failed to read {state_path:?}")),
+        };
+
+        Ok(Self {
+            inner: Arc::new(Inner {
+                state_path,
+                next: AtomicU64::new(next),
+                save_lock: tokio::sync::Mutex::new(()),
+            }),
+        })
+    }
+
+    async fn save(&self) -> Result<()> {
+        let _guard = self.inner.save_lock.lock().await;
+        let state = SequenceState {
+            next: self.inner.next.load(Ordering::SeqCst),
+        };
+
+        let temp_path = self.inner.state_path.with_extension("json.tmp");
+        tokio::fs::write(&temp_path, serde_json::to_vec(&state)?).await?;
+        tokio::fs::rename(&temp_path, &self.inner.state_path).await?;
+
+        Ok(())
+    }
+}
+
+impl NamingStrategy for SequentialNames {
+    fn propose(&self, _upload: &NamingInput<'_>, _attempt: usize) -> String {
+        self.inner.next.fetch_add(1, Ordering::SeqCst).to_string()
+    }
+
+    fn save(&self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(self.save())
+    }
+}
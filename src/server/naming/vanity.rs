@@ -0,0 +1,33 @@
+use anyhow::{Result, ensure};
+
+use crate::server::naming::{NamingInput, NamingStrategy};
+
+const MAX_VANITY_LENGTH: usize = 64;
+
+/// A name picked by the uploader, with `-2`, `-3`, ... appended while it's taken.
+pub struct VanityName {
+    pub name: String,
+}
+
+impl NamingStrategy for VanityName {
+    fn propose(&self, _upload: &NamingInput<'_>, attempt: usize) -> String {
+        if attempt == 0 {
+            self.name.clone()
+        } else {
+            format!("{}-{}", self.name, attempt + 1)
+        }
+    }
+}
+
+pub fn validate_vanity_name(name: &str) -> Result<()> {
+    ensure!(
+        !name.is_empty() && name.len() <= MAX_VANITY_LENGTH,
+        "name must be between 1 and {MAX_VANITY_LENGTH} characters"
+    );
+    ensure!(
+        name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') && !name.starts_with('-'),
+        "name may only contain ASCII letters, digits and dashes, and not start with a dash"
+    );
+
+    Ok(())
+}
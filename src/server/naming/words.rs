@@ -1,61 +1,218 @@
 use std::{
-    sync::{LazyLock, Mutex},
-    time::Instant,
+    collections::HashSet,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
 };
 
-use rand::seq::SliceRandom;
+use anyhow::{Context, Result, bail, ensure};
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
 use tracing::{debug, warn};
 
-static COMBINATIONS: LazyLock<Mutex<Vec<[u16; 3]>>> = LazyLock::new(|| {
-    let start_time = Instant::now();
+use crate::server::naming::{NamingInput, NamingStrategy};
 
-    let mut combinations = Vec::new();
+/// Where the permutation key and cursor are kept so the order continues after a restart.
+const STATE_FILE: &str = ".naming.json";
 
-    for i in 0..WORD_GROUPS[0].len() {
-        for j in 0..WORD_GROUPS[1].len() {
-            for k in 0..WORD_GROUPS[2].len() {
-                combinations.push([i as u16, j as u16, k as u16]);
+const FEISTEL_ROUNDS: usize = 4;
+
+/// Hands out word combinations in a random order without repeating, using a keyed
+/// permutation of the index space instead of storing every combination.
+#[derive(Clone)]
+pub struct WordNames {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    groups: Vec<Vec<String>>,
+    state_path: PathBuf,
+    state: Mutex<PermutationState>,
+    save_lock: tokio::sync::Mutex<()>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PermutationState {
+    /// Number of combinations the key was made for, a new key is needed if it changes.
+    space: u64,
+    keys: [u64; FEISTEL_ROUNDS],
+    cursor: u64,
+}
+
+impl PermutationState {
+    fn new(space: u64) -> Self {
+        Self {
+            space,
+            keys: rand::random(),
+            cursor: 0,
+        }
+    }
+
+    fn next_index(&mut self) -> u64 {
+        if self.cursor >= self.space {
+            warn!("No more combinations available, wrapping around with a new order");
+            *self = Self::new(self.space);
+        }
+
+        let index = permute(self.cursor, self.space, &self.keys);
+        self.cursor += 1;
+        index
+    }
+}
+
+impl WordNames {
+    /// Uses one word from each of `word_lists` per name, or the built-in groups if empty.
+    pub async fn load(dir: PathBuf, word_lists: &[PathBuf]) -> Result<Self> {
+        let groups = if word_lists.is_empty() {
+            WORD_GROUPS
+                .iter()
+                .map(|group| group.iter().map(|word| word.to_string()).collect())
+                .collect()
+        } else {
+            let mut groups = Vec::with_capacity(word_lists.len());
+            for path in word_lists {
+                groups.push(load_word_group(path).await?);
             }
+            groups
+        };
+
+        let space = groups
+            .iter()
+            .try_fold(1u64, |space, group| space.checked_mul(group.len() as u64))
+            .context("too many word combinations")?;
+
+        let state_path = dir.join(STATE_FILE);
+        let state = match tokio::fs::read(&state_path).await {
+            Ok(contents) => match serde_json::from_slice::<PermutationState>(&contents) {
+                Ok(state) if state.space == space => state,
+                Ok(_) => {
+                    debug!("word lists changed, starting a new order");
+                    PermutationState::new(space)
+                }
+                Err(e) => {
+                    warn!("Failed to parse {state_path:?}, starting a new order: {e}");
+                    PermutationState::new(space)
+                }
+            },
+            Err(e) if e.kind() == ErrorKind::NotFound => PermutationState::new(space),
+            Err(e) => return Err(e).with_context(|| format!("failed to read {state_path:?}")),
+        };
+        debug!("continuing at combination {} of {space}", state.cursor);
+
+        Ok(Self {
+            inner: Arc::new(Inner {
+                groups,
+                state_path,
+                state: Mutex::new(state),
+                save_lock: tokio::sync::Mutex::new(()),
+            }),
+        })
+    }
+
+    pub fn next(&self) -> String {
+        let mut index = self.inner.state.lock().unwrap().next_index();
+
+        // decompose the index as a mixed-radix number, one digit per group
+        let mut words = Vec::with_capacity(self.inner.groups.len());
+        for group in self.inner.groups.iter().rev() {
+            let len = group.len() as u64;
+            words.push(group[(index % len) as usize].as_str());
+            index /= len;
         }
+        words.reverse();
+
+        words.join("-")
     }
 
-    combinations.shuffle(&mut rand::rng());
+    /// Writes the current cursor to disk, it's fine if a crash loses the last few.
+    pub async fn save(&self) -> Result<()> {
+        let _guard = self.inner.save_lock.lock().await;
+        let state = self.inner.state.lock().unwrap().clone();
 
-    debug!("took {:?} to compute combinations", start_time.elapsed());
+        let temp_path = self.inner.state_path.with_extension("json.tmp");
+        tokio::fs::write(&temp_path, serde_json::to_vec(&state)?).await?;
+        tokio::fs::rename(&temp_path, &self.inner.state_path).await?;
 
-    Mutex::new(combinations)
-});
+        Ok(())
+    }
+}
 
-static CURRENT_INDEX: LazyLock<Mutex<usize>> = LazyLock::new(|| Mutex::new(0));
+/// Reads one word per line, skipping blank lines.
+///
+/// Words end up in URLs and are joined with `-`, so only ASCII letters and digits are
+/// allowed, which also keeps every combination distinct.
+async fn load_word_group(path: &Path) -> Result<Vec<String>> {
+    let contents = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("failed to read word list {path:?}"))?;
 
-pub fn init_combinations() {
-    drop(COMBINATIONS.lock().unwrap());
-    drop(CURRENT_INDEX.lock().unwrap());
+    let mut group = Vec::new();
+    let mut seen = HashSet::new();
+    for (i, line) in contents.lines().enumerate() {
+        let word = line.trim();
+        if word.is_empty() {
+            continue;
+        }
+        if !word.chars().all(|c| c.is_ascii_alphanumeric()) {
+            bail!(
+                "{path:?} line {}: {word:?} may only contain ASCII letters and digits",
+                i + 1
+            );
+        }
+        if !seen.insert(word) {
+            bail!("{path:?} line {}: {word:?} is a duplicate", i + 1);
+        }
+        group.push(word.to_string());
+    }
+    ensure!(!group.is_empty(), "word list {path:?} is empty");
+
+    Ok(group)
 }
 
-pub fn get_random_word_string() -> String {
-    let combinations = COMBINATIONS.lock().unwrap();
-    let mut current_index = CURRENT_INDEX.lock().unwrap();
-
-    if let Some(combination) = combinations.get(*current_index) {
-        *current_index += 1;
-        [
-            WORD_GROUPS[0][combination[0] as usize],
-            WORD_GROUPS[1][combination[1] as usize],
-            WORD_GROUPS[2][combination[2] as usize],
-        ]
-        .join("-")
-    } else {
-        warn!("No more combinations available, wrapping around");
-
-        *current_index = 0;
-        drop(combinations);
-        drop(current_index);
-
-        get_random_word_string()
+impl NamingStrategy for WordNames {
+    fn propose(&self, _upload: &NamingInput<'_>, _attempt: usize) -> String {
+        self.next()
+    }
+
+    fn save(&self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(self.save())
     }
 }
 
+/// Maps `index` to a unique value below `space`.
+///
+/// A balanced Feistel network is a bijection on the smallest even power of two that
+/// covers `space`; results outside of `space` are fed back in until they land inside
+/// ("cycle walking"), which keeps it a bijection on `0..space`.
+fn permute(index: u64, space: u64, keys: &[u64; FEISTEL_ROUNDS]) -> u64 {
+    let bits = (u64::BITS - space.saturating_sub(1).leading_zeros()).max(2);
+    let half_bits = bits.div_ceil(2);
+    let mask = (1u64 << half_bits) - 1;
+
+    let mut value = index;
+    loop {
+        let mut left = value >> half_bits;
+        let mut right = value & mask;
+        for key in keys {
+            let next_right = left ^ (mix(right ^ key) & mask);
+            left = right;
+            right = next_right;
+        }
+        value = (left << half_bits) | right;
+
+        if value < space {
+            return value;
+        }
+    }
+}
+
+/// splitmix64 finalizer, cheap and good enough to scramble the round inputs.
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
 const WORD_GROUPS: &[&[&str]] = &[
     &[
         "abby",
@@ -739,3 +896,62 @@ const WORD_GROUPS: &[&[&str]] = &[
         "zirconium",
     ],
 ];
+
+#[test]
+fn test_permute_is_bijective() {
+    let keys = rand::random();
+    for space in [1, 2, 3, 7, 100, 1000, 4096, 5000] {
+        let mut seen = vec![false; space as usize];
+        for index in 0..space {
+            let value = permute(index, space, &keys);
+            assert!(!seen[value as usize], "{value} repeated in {space}");
+            seen[value as usize] = true;
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_word_names_continue_after_restart() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let dir = temp_dir.path().to_path_buf();
+
+    let names = WordNames::load(dir.clone(), &[]).await.unwrap();
+    let first = names.next();
+    assert_eq!(first.split('-').count(), WORD_GROUPS.len());
+    names.save().await.unwrap();
+
+    let reloaded = WordNames::load(dir, &[]).await.unwrap();
+    let state = reloaded.inner.state.lock().unwrap().clone();
+    assert_eq!(state.cursor, 1);
+    assert_eq!(state.keys, names.inner.state.lock().unwrap().keys);
+}
+
+#[tokio::test]
+async fn test_custom_word_lists() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let dir = temp_dir.path().to_path_buf();
+    let list = |name: &str, contents: &str| {
+        let path = dir.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path
+    };
+
+    let colors = list("colors.txt", "red\ngreen\n\n");
+    let animals = list("animals.txt", "cat\ndog\nowl\n");
+    let names = WordNames::load(dir.clone(), &[colors.clone(), animals, colors.clone()])
+        .await
+        .unwrap();
+    let mut seen = HashSet::new();
+    for _ in 0..2 * 3 * 2 {
+        let name = names.next();
+        assert_eq!(name.split('-').count(), 3);
+        assert!(seen.insert(name));
+    }
+
+    let duplicates = list("duplicates.txt", "cat\ncat\n");
+    assert!(WordNames::load(dir.clone(), &[duplicates]).await.is_err());
+    let unsafe_chars = list("unsafe.txt", "cat/dog\n");
+    assert!(WordNames::load(dir.clone(), &[unsafe_chars]).await.is_err());
+    let empty = list("empty.txt", "\n");
+    assert!(WordNames::load(dir, &[empty]).await.is_err());
+}
@@ -0,0 +1,118 @@
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::{Context, Result, bail, ensure};
+use percent_encoding::percent_decode;
+use serde::{Deserialize, Serialize};
+use warp::{
+    Filter,
+    http::HeaderMap,
+    reject::{self, Rejection},
+};
+
+use crate::{
+    duration::parse_duration,
+    server::{
+        naming::{StrategyKind, validate_vanity_name},
+        routes::BadRequest,
+    },
+};
+
+/// Per-upload settings, given either as query parameters or as headers.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct UploadOptions {
+    pub expires_in: Option<Duration>,
+    pub max_downloads: Option<u32>,
+    pub naming: Option<StrategyKind>,
+    /// Name picked by the uploader instead of a generated one.
+    pub desired_name: Option<String>,
+    /// Append `-2`, `-3`, ... to `desired_name` if it's taken instead of failing.
+    pub suffix_on_conflict: bool,
+    /// Name of the file on the uploader's machine, kept in the upload's metadata.
+    pub original_name: Option<String>,
+}
+
+impl UploadOptions {
+    fn parse(query: &HashMap<String, String>, headers: &HeaderMap) -> Result<Self> {
+        let get = |query_key: &str, header_name: &str| {
+            query
+                .get(query_key)
+                .map(|s| s.as_str())
+                .or_else(|| headers.get(header_name)?.to_str().ok())
+        };
+
+        let mut options = Self::default();
+        if let Some(expires_in) = get("expires", "expires-in") {
+            let expires_in = parse_duration(expires_in)?;
+            ensure!(
+                !expires_in.is_zero(),
+                "expiry must be longer than 0 seconds"
+            );
+            options.expires_in = Some(expires_in);
+        }
+        if let Some(max_downloads) = get("downloads", "max-downloads") {
+            let max_downloads = max_downloads
+                .parse()
+                .with_context(|| format!("invalid download limit {max_downloads:?}"))?;
+            ensure!(max_downloads > 0, "download limit must be at least 1");
+            options.max_downloads = Some(max_downloads);
+        }
+        if let Some(naming) = get("naming", "naming-strategy") {
+            options.naming = Some(naming.parse()?);
+        }
+        if let Some(name) = get("name", "desired-name") {
+            validate_vanity_name(name)?;
+            options.desired_name = Some(name.to_string());
+        }
+        if let Some(on_conflict) = get("on_conflict", "name-conflict") {
+            options.suffix_on_conflict = match on_conflict {
+                "suffix" => true,
+                "reject" => false,
+                _ => bail!("name conflict handling must be \"suffix\" or \"reject\""),
+            };
+        }
+
+        // headers can't hold arbitrary unicode, so the header is percent-encoded
+        let original_name = match query.get("filename") {
+            Some(name) => Some(name.clone()),
+            None => headers
+                .get("upload-filename")
+                .map(|value| {
+                    percent_decode(value.as_bytes())
+                        .decode_utf8()
+                        .map(|name| name.into_owned())
+                        .context("file name must be percent-encoded utf8")
+                })
+                .transpose()?,
+        };
+        if let Some(name) = original_name {
+            options.original_name = Some(validate_original_name(name)?);
+        }
+
+        Ok(options)
+    }
+}
+
+/// Keeps file names that would mess up logs or metadata out.
+pub fn validate_original_name(name: String) -> Result<String> {
+    ensure!(
+        !name.is_empty() && name.len() <= 255,
+        "file name must be 1 to 255 bytes long"
+    );
+    ensure!(
+        !name.chars().any(char::is_control),
+        "file name must not contain control characters"
+    );
+
+    Ok(name)
+}
+
+pub fn upload_options() -> impl Filter<Extract = (UploadOptions,), Error = Rejection> + Clone {
+    warp::query::<HashMap<String, String>>()
+        .and(warp::header::headers_cloned())
+        .and_then(
+            |query: HashMap<String, String>, headers: HeaderMap| async move {
+                UploadOptions::parse(&query, &headers)
+                    .map_err(|e| reject::custom(BadRequest(format!("{e:#}"))))
+            },
+        )
+}
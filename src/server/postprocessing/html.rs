@@ -1,18 +1,21 @@
 use anyhow::{Context, Result};
 use headers::{ContentType, HeaderMapExt};
-use tokio::fs;
 use warp::reply::Response;
 
-const HTML: &str = include_str!("./html.html");
-const HTML_HEAD: &str = include_str!("./html_head.html");
+use crate::server::{contents::Stored, postprocessing::Templates};
 
-pub async fn process_html(f: warp::fs::File) -> Result<Response> {
+pub async fn process_html(
+    f: warp::fs::File,
+    stored: Stored<'_>,
+    templates: &Templates,
+) -> Result<Response> {
     let file_name = f.path().file_name().context("file_name() None")?;
-    let contents = fs::read_to_string(f.path()).await?;
+    let contents = stored.read_to_string(f.path()).await?;
 
     let (title, description) = (file_name.to_str().context("to_str() None")?, "");
 
-    let html_head = HTML_HEAD
+    let html_head = templates
+        .html_head
         .replace("%TITLE%", title)
         .replace("%DESCRIPTION%", description);
 
@@ -32,7 +35,9 @@ pub async fn process_html(f: warp::fs::File) -> Result<Response> {
                 r#"font-family: 'Hack Nerd Font', 'Hack', monospace"#,
             )
     } else {
-        HTML.replace("%HEAD%", &html_head)
+        templates
+            .html
+            .replace("%HEAD%", &html_head)
             .replace("%CONTENTS%", &contents)
     };
     let mut resp = Response::new(html.into());
@@ -2,14 +2,17 @@ use std::str::Lines;
 
 use anyhow::{Context, Result};
 use headers::{ContentType, HeaderMapExt};
-use tokio::fs;
 use warp::reply::Response;
 
-const HTML: &str = include_str!("./md.html");
+use crate::server::{contents::Stored, postprocessing::Templates};
 
-pub async fn process_markdown(f: warp::fs::File) -> Result<Response> {
+pub async fn process_markdown(
+    f: warp::fs::File,
+    stored: Stored<'_>,
+    templates: &Templates,
+) -> Result<Response> {
     let file_name = f.path().file_name().context("file_name() None")?;
-    let contents = fs::read_to_string(f.path()).await?;
+    let contents = stored.read_to_string(f.path()).await?;
 
     let mut lines = contents.lines();
     let (title, description) = if let Some(line) = lines.find(|line| line.starts_with("# ")) {
@@ -22,7 +25,9 @@ pub async fn process_markdown(f: warp::fs::File) -> Result<Response> {
     };
 
     let mut resp = Response::new(
-        HTML.replace("%TITLE%", title)
+        templates
+            .md
+            .replace("%TITLE%", title)
             .replace("%DESCRIPTION%", &description)
             .replace("%CONTENTS%", &contents)
             .into(),
@@ -1,18 +1,141 @@
 mod html;
 mod md;
 
-use anyhow::{Context, Result};
+use std::{io::ErrorKind, path::Path};
+
+use anyhow::{Context, Result, bail, ensure};
 use warp::reply::Reply;
 
-use crate::server::postprocessing::{html::process_html, md::process_markdown};
+use crate::server::{
+    contents::Stored,
+    metadata::guess_mime,
+    postprocessing::{html::process_html, md::process_markdown},
+};
+
+/// Which postprocessors run on served files, all of them by default.
+#[derive(Debug, Clone, Copy)]
+pub struct Postprocessors {
+    /// Render `.md` files as HTML.
+    pub markdown: bool,
+    /// Wrap `.html` files with the shared head.
+    pub html: bool,
+}
+
+impl Default for Postprocessors {
+    fn default() -> Self {
+        Self {
+            markdown: true,
+            html: true,
+        }
+    }
+}
+
+impl Postprocessors {
+    /// Enables only the postprocessors named in `names`.
+    pub fn from_names<S: AsRef<str>>(names: &[S]) -> Result<Self> {
+        let mut postprocessors = Self {
+            markdown: false,
+            html: false,
+        };
+        for name in names {
+            match name.as_ref() {
+                "markdown" | "md" => postprocessors.markdown = true,
+                "html" => postprocessors.html = true,
+                name => bail!("unknown postprocessor {name:?}"),
+            }
+        }
 
-pub async fn process(f: warp::fs::File) -> Result<impl Reply> {
-    let ext = f.path().extension().context("extension() None")?;
-    let reply: Box<dyn Reply> = match ext.to_str().context("to_str() None")? {
-        "md" => Box::new(process_markdown(f).await?),
-        "html" => Box::new(process_html(f).await?),
-        _ => Box::new(f),
+        Ok(postprocessors)
+    }
+}
+
+/// The HTML the postprocessors fill in.
+#[derive(Debug, Clone)]
+pub struct Templates {
+    md: String,
+    html: String,
+    html_head: String,
+}
+
+impl Default for Templates {
+    fn default() -> Self {
+        Self {
+            md: include_str!("./md.html").to_string(),
+            html: include_str!("./html.html").to_string(),
+            html_head: include_str!("./html_head.html").to_string(),
+        }
+    }
+}
+
+impl Templates {
+    /// Reads `md.html`, `html.html` and `html_head.html` from `dir`, keeping the built-in
+    /// template for each one that isn't there.
+    pub async fn load(dir: Option<&Path>) -> Result<Self> {
+        let mut templates = Self::default();
+        let Some(dir) = dir else {
+            return Ok(templates);
+        };
+        ensure!(dir.is_dir(), "templates directory {dir:?} does not exist");
+
+        for (name, template) in [
+            ("md.html", &mut templates.md),
+            ("html.html", &mut templates.html),
+            ("html_head.html", &mut templates.html_head),
+        ] {
+            let path = dir.join(name);
+            match tokio::fs::read_to_string(&path).await {
+                Ok(contents) => *template = contents,
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e).with_context(|| format!("failed to read {path:?}")),
+            }
+        }
+
+        Ok(templates)
+    }
+}
+
+/// Picks the postprocessor by the MIME type recorded for the upload, falling back to
+/// the extension for uploads without one.
+///
+/// The postprocessors get the plain contents of compressed or encrypted uploads, other
+/// uploads are served the way [`Stored::serve`] picks.
+pub async fn process(
+    f: warp::fs::File,
+    mime: Option<&str>,
+    stored: Stored<'_>,
+    accept_encoding: Option<&str>,
+    enabled: Postprocessors,
+    templates: &Templates,
+) -> Result<impl Reply> {
+    let mime = match mime {
+        Some(mime) => mime.to_string(),
+        None => {
+            let ext = f.path().extension().context("extension() None")?;
+            guess_mime(ext.to_str().context("to_str() None")?)
+        }
+    };
+    let reply: Box<dyn Reply> = match mime.as_str() {
+        "text/markdown" if enabled.markdown => {
+            Box::new(process_markdown(f, stored, templates).await?)
+        }
+        "text/html" if enabled.html => Box::new(process_html(f, stored, templates).await?),
+        mime => Box::new(stored.serve(f, mime, accept_encoding).await?),
     };
 
     Ok(reply)
 }
+
+#[tokio::test]
+async fn test_templates_fall_back_to_built_in() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    tokio::fs::write(temp_dir.path().join("md.html"), "%CONTENTS%")
+        .await
+        .unwrap();
+
+    let templates = Templates::load(Some(temp_dir.path())).await.unwrap();
+    assert_eq!(templates.md, "%CONTENTS%");
+    assert_eq!(templates.html, Templates::default().html);
+
+    let missing = temp_dir.path().join("missing");
+    assert!(Templates::load(Some(&missing)).await.is_err());
+}
@@ -0,0 +1,312 @@
+use std::{
+    collections::HashSet,
+    fmt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use anyhow::Result;
+use tracing::{debug, info, warn};
+use warp::reject;
+
+use crate::server::{
+    expiry::Expiry,
+    metadata::MetadataStore,
+    storage::{file_id, is_hidden_name, remove_upload},
+};
+
+/// Keeps track of how many bytes the uploads under a storage directory use.
+#[derive(Clone)]
+pub struct Quota {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    dir: PathBuf,
+    metadata: MetadataStore,
+    expiry: Expiry,
+    limits: Mutex<Limits>,
+    used: Mutex<u64>,
+    evict_lock: tokio::sync::Mutex<()>,
+}
+
+#[derive(Clone, Copy)]
+struct Limits {
+    limit: Option<u64>,
+    evict: bool,
+}
+
+#[derive(Debug)]
+pub struct QuotaExceeded;
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "storage quota exceeded")
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+impl reject::Reject for QuotaExceeded {}
+
+impl Quota {
+    /// Sums up the size of every upload in `dir`, counting uploads sharing their contents
+    /// only once.
+    ///
+    /// With `evict`, the oldest uploads that expire anyway are deleted early to make room
+    /// instead of rejecting new ones.
+    pub async fn load(
+        dir: PathBuf,
+        metadata: MetadataStore,
+        expiry: Expiry,
+        limit: Option<u64>,
+        evict: bool,
+    ) -> Result<Self> {
+        let mut used = 0;
+        let mut seen = HashSet::new();
+        for upload in list_uploads(&dir).await? {
+            if upload.id.is_none_or(|id| seen.insert(id)) {
+                used += upload.size;
+            }
+        }
+        debug!("uploads use {used} bytes");
+
+        Ok(Self {
+            inner: Arc::new(Inner {
+                dir,
+                metadata,
+                expiry,
+                limits: Mutex::new(Limits { limit, evict }),
+                used: Mutex::new(used),
+                evict_lock: tokio::sync::Mutex::new(()),
+            }),
+        })
+    }
+
+    /// Applies to reservations from now on, uploads already over a lowered limit are kept.
+    pub fn set_limit(&self, limit: Option<u64>, evict: bool) {
+        *self.inner.limits.lock().unwrap() = Limits { limit, evict };
+    }
+
+    pub fn reservation(&self) -> Reservation {
+        Reservation {
+            quota: self.clone(),
+            size: 0,
+        }
+    }
+
+    fn try_add(&self, size: u64) -> bool {
+        let limit = self.inner.limits.lock().unwrap().limit;
+        let mut used = self.inner.used.lock().unwrap();
+        if limit.is_some_and(|limit| used.saturating_add(size) > limit) {
+            return false;
+        }
+        *used += size;
+        true
+    }
+
+    pub fn release(&self, size: u64) {
+        let mut used = self.inner.used.lock().unwrap();
+        *used = used.saturating_sub(size);
+    }
+
+    async fn add(&self, size: u64) -> Result<()> {
+        if self.try_add(size) {
+            return Ok(());
+        }
+        let Limits { limit, evict } = *self.inner.limits.lock().unwrap();
+        if !evict || limit.is_some_and(|limit| size > limit) {
+            return Err(QuotaExceeded.into());
+        }
+
+        let _guard = self.inner.evict_lock.lock().await;
+        // by upload time, since deduplicated uploads share the modification time of their
+        // blob, and only uploads with a sidecar have an expiry that eviction brings forward
+        let mut uploads = Vec::new();
+        for Upload { name, modified, .. } in list_uploads(&self.inner.dir).await? {
+            match self.inner.metadata.read(&name).await {
+                Ok(Some(metadata)) => {
+                    uploads.push((metadata.uploaded_at.unwrap_or(modified), name))
+                }
+                Ok(None) => {}
+                Err(e) => warn!("Not evicting {name} with unreadable metadata: {e}"),
+            }
+        }
+        uploads.sort();
+
+        let mut uploads = uploads.into_iter();
+        while !self.try_add(size) {
+            let Some((_, name)) = uploads.next() else {
+                return Err(QuotaExceeded.into());
+            };
+
+            info!("Evicting {name} to stay within the storage quota");
+            match remove_upload(&self.inner.dir, &self.inner.metadata, self, &name).await {
+                Ok(()) => self.inner.expiry.unschedule(&name),
+                Err(e) => warn!("Failed to evict file {name}: {e}"),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Bytes accounted for an upload in progress, given back unless the upload is committed.
+pub struct Reservation {
+    quota: Quota,
+    size: u64,
+}
+
+impl Reservation {
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub async fn grow(&mut self, size: u64) -> Result<()> {
+        self.quota.add(size).await?;
+        self.size += size;
+
+        Ok(())
+    }
+
+    /// Grows or shrinks the reservation to `size`, e.g. after the upload was compressed.
+    pub async fn resize(&mut self, size: u64) -> Result<()> {
+        if size > self.size {
+            self.grow(size - self.size).await?;
+        } else {
+            self.quota.release(self.size - size);
+            self.size = size;
+        }
+
+        Ok(())
+    }
+
+    pub fn commit(mut self) {
+        self.size = 0;
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.quota.release(self.size);
+    }
+}
+
+struct Upload {
+    name: String,
+    size: u64,
+    modified: SystemTime,
+    /// Same for uploads sharing their contents through a blob.
+    id: Option<(u64, u64)>,
+}
+
+async fn list_uploads(dir: &Path) -> Result<Vec<Upload>> {
+    let mut uploads = Vec::new();
+
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        let file_metadata = entry.metadata().await?;
+        if is_hidden_name(&name) || !file_metadata.is_file() {
+            continue;
+        }
+        uploads.push(Upload {
+            name,
+            size: file_metadata.len(),
+            modified: file_metadata.modified()?,
+            id: file_id(&file_metadata),
+        });
+    }
+
+    Ok(uploads)
+}
+
+#[tokio::test]
+async fn test_quota_evicts_oldest() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let dir = temp_dir.path().to_path_buf();
+    tokio::fs::write(dir.join("old.txt"), "aaaaaa")
+        .await
+        .unwrap();
+
+    let metadata = MetadataStore::new(dir.clone());
+    let expiry = Expiry::load(dir.clone(), metadata.clone(), Default::default())
+        .await
+        .unwrap();
+    let quota = Quota::load(
+        dir.clone(),
+        metadata.clone(),
+        expiry.clone(),
+        Some(10),
+        true,
+    )
+    .await
+    .unwrap();
+
+    let mut reservation = quota.reservation();
+    reservation.grow(6).await.unwrap();
+    reservation.commit();
+    assert!(!dir.join("old.txt").exists());
+
+    let strict = Quota::load(dir.clone(), metadata, expiry, Some(10), false)
+        .await
+        .unwrap();
+    assert!(strict.reservation().grow(11).await.is_err());
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_quota_evicts_deduplicated_uploads() {
+    use crate::server::{
+        metadata::Metadata,
+        storage::{BLOB_DIR, persist_upload},
+    };
+
+    let temp_dir = tempfile::tempdir().unwrap();
+    let dir = temp_dir.path().to_path_buf();
+    let metadata = MetadataStore::new(dir.clone());
+    let upload = |name: &'static str, contents: &'static str, uploaded_at: SystemTime| {
+        let dir = dir.clone();
+        let metadata = metadata.clone();
+        async move {
+            let mut upload_metadata = Metadata::new(SystemTime::now());
+            upload_metadata.uploaded_at = Some(uploaded_at);
+            upload_metadata.sha256 = Some(contents.to_string());
+            let mut temp_file = tempfile::NamedTempFile::new_in(&dir).unwrap();
+            std::io::Write::write_all(&mut temp_file, contents.as_bytes()).unwrap();
+            persist_upload(
+                temp_file.into_temp_path(),
+                &dir,
+                &metadata,
+                &upload_metadata,
+                1,
+                |_| name.to_string(),
+            )
+            .await
+            .unwrap();
+        }
+    };
+    // written first, but uploaded last
+    upload("new.txt", "newer", SystemTime::now()).await;
+    upload("old-1.txt", "same", SystemTime::UNIX_EPOCH).await;
+    upload("old-2.txt", "same", SystemTime::UNIX_EPOCH).await;
+
+    let expiry = Expiry::load(dir.clone(), metadata.clone(), Default::default())
+        .await
+        .unwrap();
+    let quota = Quota::load(dir.clone(), metadata, expiry, Some(10), true)
+        .await
+        .unwrap();
+
+    // the shared contents only make room once both names are gone
+    let mut reservation = quota.reservation();
+    reservation.grow(5).await.unwrap();
+    reservation.commit();
+    assert!(!dir.join("old-1.txt").exists());
+    assert!(!dir.join("old-2.txt").exists());
+    assert!(!dir.join(BLOB_DIR).join("same").exists());
+    assert!(dir.join("new.txt").exists());
+}
@@ -0,0 +1,318 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result, ensure};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tempfile::TempPath;
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncReadExt, AsyncWriteExt},
+};
+use tracing::{debug, info, warn};
+use warp::reject;
+
+use crate::server::{
+    auth::{TokenPolicy, Tokens, generate_token},
+    metadata::sync_dir,
+    options::UploadOptions,
+    quota::{Quota, Reservation},
+};
+
+/// Sessions keep their data and what's needed to resume them here, so they survive a
+/// restart.
+pub const SESSION_DIR: &str = ".sessions";
+/// Sessions without a request for this long are dropped along with their data.
+const SESSION_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+/// How often to look for sessions that timed out.
+const REAP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// An upload sent in several requests, each appending to what was received so far.
+pub struct Session {
+    pub ext: String,
+    pub options: UploadOptions,
+    pub policy: Arc<TokenPolicy>,
+    /// Total size announced when the session was created.
+    pub length: u64,
+    /// Bytes received so far.
+    pub offset: u64,
+    pub file: File,
+    pub temp_path: TempPath,
+    pub hasher: Sha256,
+    /// Covers `length` from the start, so a session can't run out of quota halfway.
+    pub reservation: Reservation,
+    pub last_active: Instant,
+    /// The [`SavedSession`], deleted along with the session.
+    saved_path: TempPath,
+}
+
+/// What a session is restored from after a restart, besides the data received so far.
+#[derive(Serialize, Deserialize)]
+struct SavedSession {
+    ext: String,
+    options: UploadOptions,
+    /// The token is looked up again by its label.
+    token_label: String,
+    length: u64,
+}
+
+/// A session is taken out once complete, so requests still waiting for it see `None`.
+pub type SessionSlot = Arc<tokio::sync::Mutex<Option<Session>>>;
+
+/// Resumable uploads in progress, kept until complete or abandoned.
+#[derive(Clone)]
+pub struct Sessions {
+    dir: PathBuf,
+    slots: Arc<Mutex<HashMap<String, SessionSlot>>>,
+}
+
+impl Sessions {
+    /// Restores the sessions saved in `dir` when the server last stopped, reserving quota
+    /// for them again.
+    ///
+    /// Sessions that can't be resumed anymore, e.g. because their token was removed, are
+    /// dropped along with their data.
+    pub async fn load(dir: PathBuf, quota: &Quota, tokens: &Tokens) -> Result<Self> {
+        let sessions = Self {
+            dir,
+            slots: Default::default(),
+        };
+        let session_dir = sessions.dir.join(SESSION_DIR);
+        let mut entries = match tokio::fs::read_dir(&session_dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(sessions),
+            Err(e) => return Err(e).with_context(|| format!("failed to read {session_dir:?}")),
+        };
+
+        let mut restored = 0;
+        while let Some(entry) = entries.next_entry().await? {
+            let file_name = entry.file_name();
+            let Some(id) = file_name.to_str().and_then(|s| s.strip_suffix(".json")) else {
+                continue;
+            };
+            let data_path = session_dir.join(id);
+            match restore(&entry.path(), &data_path, quota, tokens).await {
+                Ok(session) => {
+                    sessions.slots.lock().unwrap().insert(
+                        id.to_string(),
+                        Arc::new(tokio::sync::Mutex::new(Some(session))),
+                    );
+                    restored += 1;
+                }
+                Err(e) => {
+                    warn!("Dropping resumable upload {id}: {e:#}");
+                    for path in [entry.path(), data_path] {
+                        match tokio::fs::remove_file(&path).await {
+                            Err(e) if e.kind() != ErrorKind::NotFound => {
+                                warn!("Failed to remove {path:?}: {e}");
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+            }
+        }
+        // data without a saved session belongs to one that finished or never got going
+        let mut entries = tokio::fs::read_dir(&session_dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_none()
+                && !tokio::fs::try_exists(path.with_extension("json")).await?
+            {
+                debug!("removing stale session data {path:?}");
+                tokio::fs::remove_file(&path).await?;
+            }
+        }
+        if restored > 0 {
+            info!("Restored {restored} resumable uploads");
+        }
+
+        Ok(sessions)
+    }
+
+    /// Starts a session under a new random id, saving it so it can be resumed after a
+    /// restart.
+    pub async fn create(
+        &self,
+        ext: String,
+        options: UploadOptions,
+        policy: Arc<TokenPolicy>,
+        length: u64,
+        reservation: Reservation,
+    ) -> Result<String> {
+        let id = generate_token();
+        let session_dir = self.dir.join(SESSION_DIR);
+        tokio::fs::create_dir_all(&session_dir).await?;
+
+        let data_path = session_dir.join(&id);
+        let file = File::create_new(&data_path).await?;
+        let temp_path = TempPath::try_from_path(&data_path)?;
+
+        let saved = SavedSession {
+            ext,
+            options,
+            token_label: policy.label.clone(),
+            length,
+        };
+        let saved_path = session_dir.join(format!("{id}.json"));
+        let mut saved_file = File::create_new(&saved_path).await?;
+        let saved_path = TempPath::try_from_path(&saved_path)?;
+        saved_file.write_all(&serde_json::to_vec(&saved)?).await?;
+        saved_file.sync_all().await?;
+        tokio::task::spawn_blocking(move || sync_dir(&session_dir)).await??;
+
+        let session = Session {
+            ext: saved.ext,
+            options: saved.options,
+            policy,
+            length,
+            offset: 0,
+            file,
+            temp_path,
+            hasher: Sha256::new(),
+            reservation,
+            last_active: Instant::now(),
+            saved_path,
+        };
+        self.slots
+            .lock()
+            .unwrap()
+            .insert(id.clone(), Arc::new(tokio::sync::Mutex::new(Some(session))));
+
+        Ok(id)
+    }
+
+    pub fn get(&self, id: &str) -> Option<SessionSlot> {
+        self.slots.lock().unwrap().get(id).cloned()
+    }
+
+    pub fn remove(&self, id: &str) {
+        self.slots.lock().unwrap().remove(id);
+    }
+
+    /// Drops sessions that timed out, which deletes their data and releases their quota.
+    fn remove_idle(&self) {
+        self.slots
+            .lock()
+            .unwrap()
+            .retain(|_, slot| match slot.try_lock() {
+                Ok(session) => session
+                    .as_ref()
+                    .is_some_and(|session| session.last_active.elapsed() < SESSION_TIMEOUT),
+                // busy with a request right now
+                Err(_) => true,
+            });
+    }
+
+    /// Drops sessions once they time out, checking every [`REAP_INTERVAL`].
+    pub async fn run_reaper(self) {
+        let mut interval = tokio::time::interval(REAP_INTERVAL);
+        loop {
+            interval.tick().await;
+            self.remove_idle();
+        }
+    }
+
+    /// Keeps the data of every session on disk when the server shuts down, so they can
+    /// be resumed once it's back.
+    pub async fn keep(&self) {
+        let slots = std::mem::take(&mut *self.slots.lock().unwrap());
+        for (id, slot) in slots {
+            let Some(session) = slot.lock().await.take() else {
+                continue;
+            };
+            if let Err(e) = session.file.sync_all().await {
+                warn!("Failed to sync resumable upload {id}: {e}");
+            }
+            // the reservation is made again on the next start
+            if let Err(e) = session.temp_path.keep().and(session.saved_path.keep()) {
+                warn!("Failed to keep resumable upload {id}: {e}");
+            }
+        }
+    }
+}
+
+/// Picks a saved session back up where its data ends.
+async fn restore(
+    saved_path: &Path,
+    data_path: &Path,
+    quota: &Quota,
+    tokens: &Tokens,
+) -> Result<Session> {
+    let saved: SavedSession = serde_json::from_slice(&tokio::fs::read(saved_path).await?)
+        .context("failed to parse saved session")?;
+    let policy = tokens
+        .get(&saved.token_label)
+        .with_context(|| format!("token {:?} no longer exists", saved.token_label))?;
+
+    // the data can only be trusted as far as it made it to disk, anything after that is
+    // sent again
+    let mut hasher = Sha256::new();
+    let mut offset = 0;
+    let mut data = File::open(data_path).await?;
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        match data.read(&mut buf).await? {
+            0 => break,
+            len => {
+                hasher.update(&buf[..len]);
+                offset += len as u64;
+            }
+        }
+    }
+    ensure!(offset <= saved.length, "more data than announced");
+
+    let mut reservation = quota.reservation();
+    reservation.grow(saved.length).await?;
+    let file = OpenOptions::new().append(true).open(data_path).await?;
+
+    Ok(Session {
+        ext: saved.ext,
+        options: saved.options,
+        policy,
+        length: saved.length,
+        offset,
+        file,
+        temp_path: TempPath::try_from_path(data_path)?,
+        hasher,
+        reservation,
+        last_active: Instant::now(),
+        saved_path: TempPath::try_from_path(saved_path)?,
+    })
+}
+
+/// Another request is appending to the same session.
+#[derive(Debug)]
+pub struct SessionBusy;
+
+impl fmt::Display for SessionBusy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "upload is busy with another request")
+    }
+}
+
+impl std::error::Error for SessionBusy {}
+
+impl reject::Reject for SessionBusy {}
+
+/// The client sent data for a different offset than the server has.
+#[derive(Debug)]
+pub struct OffsetMismatch {
+    pub expected: u64,
+}
+
+impl fmt::Display for OffsetMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "upload offset must be {}", self.expected)
+    }
+}
+
+impl std::error::Error for OffsetMismatch {}
+
+impl reject::Reject for OffsetMismatch {}
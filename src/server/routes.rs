@@ -1,7 +1,19 @@
-use std::{path::PathBuf, time::Duration};
+use std::{
+    convert::Infallible,
+    fmt,
+    path::Path,
+    sync::Arc,
+    task::Poll,
+    time::{Duration, Instant, SystemTime},
+};
 
 use anyhow::Result;
 use bytes::Buf;
+use futures::{Stream, StreamExt, TryStreamExt};
+use http_body_util::BodyExt;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tempfile::TempPath;
 use tokio::{
     fs::File,
     io::{AsyncWriteExt, BufWriter},
@@ -9,68 +21,145 @@ use tokio::{
 use tracing::{debug, info, warn};
 use warp::{
     Filter,
-    filters::{BoxedFilter, log::log},
+    filters::{BoxedFilter, log::log, path::Peek},
+    http::{
+        HeaderMap, Method, StatusCode,
+        header::{CONTENT_LENGTH, HeaderValue},
+    },
     reject::{self, MethodNotAllowed, Rejection},
-    reply::Reply,
+    reply::{Reply, Response},
 };
 
-use crate::server::{
-    naming::get_random_word_string, postprocessing::process, utils::get_temp_dir_path,
+use crate::{
+    server::{
+        auth::{TokenPolicy, generate_token, hash_token, hashes_match},
+        compression::{Encoding, compress_file, is_compressible},
+        contents::Stored,
+        encryption::{EncryptionKey, encrypt},
+        metadata::{Metadata, guess_mime},
+        naming::{NamingInput, NamingStrategy, VanityName},
+        options::{UploadOptions, upload_options, validate_original_name},
+        postprocessing::process,
+        quota::{QuotaExceeded, Reservation},
+        resumable::{OffsetMismatch, SessionBusy},
+        state::{Settings, State},
+        storage::{
+            MAX_NAME_ATTEMPTS, NameTaken, create_temp_file, encrypted_with, is_hidden_name,
+            is_valid_upload_name, persist_upload,
+        },
+        tus::{
+            OFFSET_CONTENT_TYPE, TUS_EXTENSIONS, TUS_VERSION, UnsupportedMediaType,
+            UnsupportedVersion, ext_from_metadata, offset_content_type, parse_metadata,
+            tus_resumable,
+        },
+        utils::to_hex,
+    },
+    unix_time,
 };
 
-const RETENTION_DURATION: Duration = Duration::from_secs(60 * 60 * 24 * 7); // 7 days
-
 #[derive(Debug)]
 struct ServerError;
 
 impl reject::Reject for ServerError {}
 
-pub fn get_routes(dir: PathBuf, upload_token: String) -> BoxedFilter<(impl Reply,)> {
-    let file_route = warp::fs::dir(dir.to_path_buf()).and_then(|f: warp::fs::File| async move {
-        let path = f.path().to_path_buf();
-        process(f).await.map_err(move |e| {
-            warn!("Error postprocessing {path:?}: {e}");
-            warp::reject::custom(ServerError)
-        })
-    });
-    let upload_route = warp::post()
-        .and(warp::path::param())
-        .and_then(|param: String| async move {
-            if let Some((before, ext)) = param.split_once('.') {
-                if before == "upload"
-                    && !ext.is_empty()
-                    && ext.len() <= 10
-                    && ext.chars().all(|c| c.is_ascii_alphanumeric())
-                {
-                    return Ok(ext.to_string());
-                }
-            }
+/// Rejects a request with a 400 and a message meant for the client.
+#[derive(Debug)]
+pub struct BadRequest(pub String);
+
+impl reject::Reject for BadRequest {}
+
+/// Rejects a request with a 403 and a message meant for the client.
+#[derive(Debug)]
+struct Forbidden(String);
+
+impl reject::Reject for Forbidden {}
+
+#[derive(Debug)]
+struct TooLarge;
+
+impl fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "upload is too large")
+    }
+}
+
+impl std::error::Error for TooLarge {}
+
+impl reject::Reject for TooLarge {}
+
+/// How long to wait for more of a request body before giving up on the client.
+///
+/// Appends hold their session while they run, so a stalled connection would otherwise
+/// keep it busy for good.
+const BODY_READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+struct BodyTimeout;
+
+impl fmt::Display for BodyTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "timed out waiting for the request body")
+    }
+}
 
-            Err(warp::reject::not_found())
+impl std::error::Error for BodyTimeout {}
+
+impl reject::Reject for BodyTimeout {}
+
+pub fn get_routes(state: State) -> BoxedFilter<(impl Reply,)> {
+    let file_route = warp::path::peek()
+        .and_then(|peek: Peek| async move {
+            // warp percent-decodes the path, so "%2E" would also reach a hidden directory
+            let first_segment = peek.segments().next().unwrap_or_default();
+            if is_hidden_name(first_segment)
+                || first_segment.to_ascii_lowercase().starts_with("%2e")
+            {
+                Err(warp::reject::not_found())
+            } else {
+                Ok(())
+            }
         })
+        .untuple_one()
+        .and(warp::method())
+        .and(warp::fs::dir(state.dir.clone()))
+        .and(warp::header::optional::<String>("accept-encoding"))
+        .and_then({
+            let state = state.clone();
+            move |method, f, accept_encoding| serve_file(state.clone(), method, f, accept_encoding)
+        });
+    let delete_route = warp::delete()
+        .and(warp::path::param())
         .and(warp::path::end())
         .and(warp::header::header("authorization"))
-        .and_then(move |ext: String, auth: String| {
-            let upload_token = upload_token.clone();
-            async move {
-                if auth == format!("Bearer {upload_token}") {
-                    Ok(ext)
-                } else {
-                    Err(warp::reject::not_found())
+        .and_then({
+            let state = state.clone();
+            move |name, auth| delete_file(state.clone(), name, auth)
+        });
+    let upload_route = warp::post()
+        .and(upload_target(state.clone(), "upload"))
+        .and(upload_options())
+        .and(warp::header::optional::<u64>("content-length"))
+        .and(response_format(state.clone()))
+        .and(warp::body::stream())
+        .and_then({
+            let state = state.clone();
+            move |ext, policy, options, content_length, format, stream| {
+                let state = state.clone();
+                async move {
+                    upload_file(state, ext, policy, options, content_length, stream)
+                        .await
+                        .map(|finished| finished.into_response(&format))
+                        .map_err(reject_upload_error)
                 }
             }
-        })
-        .and(warp::body::aggregate())
-        .and_then(|ext, stream| async move {
-            upload_file(ext, stream).await.map_err(|e| {
-                warn!("Error uploading file: {e}");
-                warp::reject::custom(ServerError)
-            })
         });
 
     file_route
+        .or(delete_route)
         .or(upload_route)
+        .or(tus_routes(state))
         .with(log(module_path!()))
+        .recover(recover_rejection)
         .or_else(|rejection: Rejection| async move {
             if rejection.find::<MethodNotAllowed>().is_some() {
                 Err(warp::reject::not_found())
@@ -81,42 +170,1033 @@ pub fn get_routes(dir: PathBuf, upload_token: String) -> BoxedFilter<(impl Reply
         .boxed()
 }
 
-async fn upload_file(ext: String, mut buf: impl Buf) -> Result<impl warp::Reply> {
-    let temp_dir = get_temp_dir_path().await;
+/// Resumable uploads through the tus protocol at `/files`.
+fn tus_routes(state: State) -> BoxedFilter<(Response,)> {
+    let discovery_route = warp::options()
+        .and(warp::path("files"))
+        .and(warp::path::end())
+        .map({
+            let state = state.clone();
+            move || {
+                let mut resp = StatusCode::NO_CONTENT.into_response();
+                let headers = resp.headers_mut();
+                headers.insert("tus-version", HeaderValue::from_static(TUS_VERSION));
+                headers.insert("tus-extension", HeaderValue::from_static(TUS_EXTENSIONS));
+                if let Some(max) = state.settings().config.max_upload_size {
+                    headers.insert("tus-max-size", HeaderValue::from(max));
+                }
+                resp
+            }
+        });
+    let create_route = warp::post()
+        .and(warp::path("files"))
+        .and(warp::path::end())
+        .and(tus_resumable())
+        .and(authorized(state.clone()))
+        .and(upload_options())
+        .and(warp::header::<u64>("upload-length"))
+        .and(warp::header::optional::<String>("upload-metadata"))
+        .and_then({
+            let state = state.clone();
+            move |policy: Arc<TokenPolicy>,
+                  mut options: UploadOptions,
+                  length,
+                  metadata: Option<String>| {
+                let state = state.clone();
+                async move {
+                    let bad_request =
+                        |e: anyhow::Error| reject::custom(BadRequest(format!("{e:#}")));
+                    let mut metadata = parse_metadata(metadata.as_deref().unwrap_or_default())
+                        .map_err(bad_request)?;
+                    let ext =
+                        ext_from_metadata(&metadata, state.settings().config.max_extension_length);
+                    if !policy.allows_extension(&ext) {
+                        return Err(reject::custom(Forbidden(format!(
+                            "uploading .{ext} files is not allowed"
+                        ))));
+                    }
+                    if let Some(name) = metadata.remove("filename") {
+                        options.original_name =
+                            Some(validate_original_name(name).map_err(bad_request)?);
+                    }
 
-    let filename = format!("{}.{ext}", get_random_word_string());
-    let filepath = temp_dir.join(&filename);
-    if filepath.exists() {
-        warn!("file {filename} already exists, replacing!!");
-    }
-    debug!("writing {filename}");
+                    let id = create_session(state, ext, policy, options, length)
+                        .await
+                        .map_err(reject_upload_error)?;
 
-    let bytes_written = {
-        let f = File::create(filepath).await?;
-        let mut writer = BufWriter::new(f);
-        let mut bytes_written = 0;
-        while buf.has_remaining() {
-            let chunk = buf.chunk();
-            let len = chunk.len();
-            writer.write_all(chunk).await?;
-            buf.advance(len);
-            bytes_written += len;
-        }
-        writer.flush().await?;
-        bytes_written
+                    let mut resp = StatusCode::CREATED.into_response();
+                    resp.headers_mut().insert("location", session_location(&id));
+                    Ok(resp)
+                }
+            }
+        });
+    let session_path = warp::path("files")
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(tus_resumable());
+    let offset_route = warp::head()
+        .and(session_path.clone())
+        .and(authorized(state.clone()))
+        .and_then({
+            let state = state.clone();
+            move |id, policy| {
+                let state = state.clone();
+                async move {
+                    let (offset, length) = session_progress(state, id, policy).await?;
+                    Ok::<_, Rejection>(progress_response(offset, length))
+                }
+            }
+        });
+    let append_route = warp::patch()
+        .and(session_path.clone())
+        .and(offset_content_type())
+        .and(authorized(state.clone()))
+        .and(warp::header::<u64>("upload-offset"))
+        .and(warp::body::stream())
+        .and_then({
+            let state = state.clone();
+            move |id, policy, offset, stream| {
+                let state = state.clone();
+                async move {
+                    // tus answers every append with 204, so the result goes in headers
+                    let mut resp = StatusCode::NO_CONTENT.into_response();
+                    let headers = resp.headers_mut();
+                    match append_to_session(state, id, policy, offset, stream).await? {
+                        Appended::Partial { offset } => {
+                            headers.insert("upload-offset", HeaderValue::from(offset));
+                        }
+                        Appended::Complete(finished, length) => {
+                            headers.insert("upload-offset", HeaderValue::from(length));
+                            headers.insert(
+                                "upload-name",
+                                HeaderValue::from_str(&finished.name)
+                                    .map_err(|_| reject::custom(ServerError))?,
+                            );
+                            headers.insert(
+                                "upload-sha256",
+                                HeaderValue::from_str(&finished.sha256).expect("hashes are hex"),
+                            );
+                            if let Ok(mime) = HeaderValue::from_str(&finished.mime) {
+                                headers.insert("upload-mime", mime);
+                            }
+                            finished.insert_headers(headers);
+                        }
+                    }
+                    Ok::<_, Rejection>(resp)
+                }
+            }
+        });
+    let terminate_route =
+        warp::delete()
+            .and(session_path)
+            .and(authorized(state.clone()))
+            .and_then(move |id, policy| {
+                let state = state.clone();
+                async move {
+                    Ok::<_, Rejection>(abort_session(state, id, policy).await?.into_response())
+                }
+            });
+
+    let cors = warp::cors()
+        .allow_any_origin()
+        .allow_methods([
+            Method::OPTIONS,
+            Method::POST,
+            Method::HEAD,
+            Method::PATCH,
+            Method::DELETE,
+        ])
+        .allow_headers([
+            "authorization",
+            "content-type",
+            "tus-resumable",
+            "upload-length",
+            "upload-metadata",
+            "upload-offset",
+            "expires-in",
+            "max-downloads",
+            "naming-strategy",
+            "desired-name",
+            "name-conflict",
+        ])
+        .expose_headers([
+            "location",
+            "tus-resumable",
+            "tus-version",
+            "tus-extension",
+            "tus-max-size",
+            "upload-length",
+            "upload-offset",
+            "upload-name",
+            "upload-sha256",
+            "upload-mime",
+            "delete-token",
+            "expires-at",
+        ]);
+
+    discovery_route
+        .or(create_route)
+        .unify()
+        .or(offset_route)
+        .unify()
+        .or(append_route)
+        .unify()
+        .or(terminate_route)
+        .unify()
+        // handled here so error responses carry the tus headers too
+        .recover(recover_rejection)
+        .unify()
+        .with(warp::reply::with::header("tus-resumable", TUS_VERSION))
+        .with(cors)
+        .map(Reply::into_response)
+        .boxed()
+}
+
+/// Turns the rejections of our own filters into responses.
+async fn recover_rejection(rejection: Rejection) -> Result<Response, Rejection> {
+    let (message, status) = if let Some(BadRequest(message)) = rejection.find() {
+        (message.clone(), StatusCode::BAD_REQUEST)
+    } else if let Some(Forbidden(message)) = rejection.find() {
+        (message.clone(), StatusCode::FORBIDDEN)
+    } else if rejection.find::<TooLarge>().is_some() {
+        (TooLarge.to_string(), StatusCode::PAYLOAD_TOO_LARGE)
+    } else if rejection.find::<BodyTimeout>().is_some() {
+        (BodyTimeout.to_string(), StatusCode::REQUEST_TIMEOUT)
+    } else if rejection.find::<QuotaExceeded>().is_some() {
+        (QuotaExceeded.to_string(), StatusCode::INSUFFICIENT_STORAGE)
+    } else if rejection.find::<NameTaken>().is_some() {
+        (NameTaken.to_string(), StatusCode::CONFLICT)
+    } else if rejection.find::<SessionBusy>().is_some() {
+        (SessionBusy.to_string(), StatusCode::LOCKED)
+    } else if let Some(mismatch) = rejection.find::<OffsetMismatch>() {
+        let mut resp =
+            warp::reply::with_status(mismatch.to_string(), StatusCode::CONFLICT).into_response();
+        resp.headers_mut()
+            .insert("upload-offset", HeaderValue::from(mismatch.expected));
+        return Ok(resp);
+    } else if rejection.find::<UnsupportedVersion>().is_some() {
+        let mut resp = StatusCode::PRECONDITION_FAILED.into_response();
+        resp.headers_mut()
+            .insert("tus-version", HeaderValue::from_static(TUS_VERSION));
+        return Ok(resp);
+    } else if rejection.find::<UnsupportedMediaType>().is_some() {
+        (
+            format!("content type must be {OFFSET_CONTENT_TYPE}"),
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        )
+    } else {
+        return Err(rejection);
     };
-    debug!("wrote {bytes_written} bytes to {filename}");
 
-    tokio::spawn({
-        let filename = filename.clone();
+    Ok(warp::reply::with_status(message, status).into_response())
+}
+
+fn session_location(id: &str) -> HeaderValue {
+    HeaderValue::from_str(&format!("/files/{id}")).expect("session ids are hex")
+}
+
+fn progress_response(offset: u64, length: u64) -> Response {
+    let mut resp = StatusCode::OK.into_response();
+    let headers = resp.headers_mut();
+    headers.insert("upload-offset", HeaderValue::from(offset));
+    headers.insert("upload-length", HeaderValue::from(length));
+    headers.insert("cache-control", HeaderValue::from_static("no-store"));
+    resp
+}
+
+/// Resolves the token in the authorization header to what it's allowed to do.
+fn authorized(
+    state: State,
+) -> impl Filter<Extract = (Arc<TokenPolicy>,), Error = Rejection> + Clone {
+    warp::header::header("authorization").and_then(move |auth: String| {
+        let state = state.clone();
         async move {
-            tokio::time::sleep(RETENTION_DURATION).await;
-            info!("Deleting {filename}");
-            if let Err(e) = tokio::fs::remove_file(temp_dir.join(&filename)).await {
-                warn!("Failed to delete file {filename}: {e}");
+            auth.strip_prefix("Bearer ")
+                .and_then(|token| state.settings().tokens.authenticate(token))
+                .ok_or_else(warp::reject::not_found)
+        }
+    })
+}
+
+/// How the uploader wants to hear about a finished upload.
+#[derive(Debug, Clone)]
+enum ResponseFormat {
+    /// Just the name of the upload.
+    Text,
+    /// [`UploadInfo`] with URLs starting with `base_url`.
+    Json { base_url: String },
+}
+
+/// Picks the [`ResponseFormat`] from the `Accept` header.
+fn response_format(
+    state: State,
+) -> impl Filter<Extract = (ResponseFormat,), Error = Infallible> + Clone {
+    warp::header::headers_cloned().map(move |headers: HeaderMap| {
+        let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+        let accepts_json = header("accept").is_some_and(|accept| {
+            accept.split(',').any(|media_range| {
+                let media_type = media_range.split(';').next().unwrap_or_default();
+                media_type.trim().eq_ignore_ascii_case("application/json")
+            })
+        });
+        if !accepts_json {
+            return ResponseFormat::Text;
+        }
+
+        let base_url = state
+            .settings()
+            .config
+            .public_url
+            .clone()
+            .unwrap_or_else(|| {
+                // the first proxy in front of us sets these, and only the requester sees the result
+                let scheme = header("x-forwarded-proto")
+                    .and_then(|proto| proto.split(',').next())
+                    .map_or("http", str::trim);
+                let host = header("x-forwarded-host")
+                    .or_else(|| header("host"))
+                    .unwrap_or("localhost");
+                format!("{scheme}://{host}")
+            });
+        ResponseFormat::Json { base_url }
+    })
+}
+
+/// Matches a `<prefix>.<ext>` path from a token that may upload files with that extension.
+fn upload_target(
+    state: State,
+    prefix: &'static str,
+) -> impl Filter<Extract = (String, Arc<TokenPolicy>), Error = Rejection> + Clone {
+    warp::path::param()
+        .and_then({
+            let state = state.clone();
+            move |param: String| {
+                let max_extension_length = state.settings().config.max_extension_length;
+                async move {
+                    if let Some((before, ext)) = param.split_once('.')
+                        && before == prefix
+                        && !ext.is_empty()
+                        && ext.len() <= max_extension_length
+                        && ext.chars().all(|c| c.is_ascii_alphanumeric())
+                    {
+                        return Ok(ext.to_string());
+                    }
+
+                    Err(warp::reject::not_found())
+                }
+            }
+        })
+        .and(warp::path::end())
+        .and(authorized(state))
+        .and_then(|ext: String, policy: Arc<TokenPolicy>| async move {
+            if !policy.allows_extension(&ext) {
+                return Err(warp::reject::custom(Forbidden(format!(
+                    "uploading .{ext} files is not allowed"
+                ))));
+            }
+            Ok((ext, policy))
+        })
+        .untuple_one()
+}
+
+fn reject_upload_error(e: anyhow::Error) -> Rejection {
+    if e.is::<TooLarge>() {
+        warp::reject::custom(TooLarge)
+    } else if e.is::<BodyTimeout>() {
+        warp::reject::custom(BodyTimeout)
+    } else if e.is::<QuotaExceeded>() {
+        warp::reject::custom(QuotaExceeded)
+    } else if e.is::<NameTaken>() {
+        warp::reject::custom(NameTaken)
+    } else if let Some(mismatch) = e.downcast_ref::<OffsetMismatch>() {
+        warp::reject::custom(OffsetMismatch {
+            expected: mismatch.expected,
+        })
+    } else {
+        warn!("Error uploading file: {e}");
+        warp::reject::custom(ServerError)
+    }
+}
+
+/// Counts full downloads and deletes uploads once their download limit is reached.
+async fn serve_file(
+    state: State,
+    method: Method,
+    f: warp::fs::File,
+    accept_encoding: Option<String>,
+) -> Result<Response, Rejection> {
+    let path = f.path().to_path_buf();
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(warp::reject::not_found)?
+        .to_string();
+
+    let upload_metadata = state.metadata.read(&name).await.map_err(|e| {
+        warn!("Error reading metadata of {name}: {e}");
+        warp::reject::custom(ServerError)
+    })?;
+    // reserve limited downloads before reading anything so concurrent requests can't
+    // overshoot the limit, other downloads are counted once they're done
+    let (upload_metadata, reserved) = match upload_metadata {
+        Some(metadata) if method == Method::GET && metadata.max_downloads.is_some() => {
+            let counted = state
+                .metadata
+                .update(&name, |metadata| {
+                    if metadata.downloads_exhausted() {
+                        return None;
+                    }
+                    metadata.downloads += 1;
+                    Some(metadata.clone())
+                })
+                .await
+                .map_err(|e| {
+                    warn!("Error counting download of {name}: {e}");
+                    warp::reject::custom(ServerError)
+                })?;
+            match counted {
+                Some(None) => return Err(warp::reject::not_found()),
+                Some(Some(metadata)) => (Some(metadata), true),
+                None => (None, false),
             }
         }
+        upload_metadata => (upload_metadata, false),
+    };
+    let download = (method == Method::GET && upload_metadata.is_some()).then(|| Download {
+        state: state.clone(),
+        name: name.clone(),
+        reserved,
+        last: upload_metadata
+            .as_ref()
+            .is_some_and(|metadata| reserved && metadata.downloads_exhausted()),
+        finished: false,
     });
 
-    Ok(filename)
+    let settings = state.settings();
+    let stored = Stored {
+        encoding: upload_metadata
+            .as_ref()
+            .and_then(|metadata| metadata.encoding),
+        encrypted_with: upload_metadata
+            .as_ref()
+            .and_then(|metadata| encrypted_with(&state.dir, &name, metadata)),
+        keyring: &settings.config.encryption,
+    };
+    let resp = process(
+        f,
+        upload_metadata
+            .as_ref()
+            .and_then(|metadata| metadata.mime.as_deref()),
+        stored,
+        accept_encoding.as_deref(),
+        settings.config.postprocessors,
+        &settings.templates,
+    )
+    .await
+    .map_err(|e| {
+        warn!("Error postprocessing {path:?}: {e}");
+        warp::reject::custom(ServerError)
+    })?
+    .into_response();
+
+    match download {
+        // ranges and revalidations don't count, dropping the download gives back its place
+        Some(download) if resp.status() == StatusCode::OK => Ok(download.track(resp)),
+        _ => Ok(resp),
+    }
+}
+
+/// A download that is counted once its body was sent in full.
+///
+/// Dropping it before then gives back the place it reserved under the download limit.
+struct Download {
+    state: State,
+    name: String,
+    /// Already counted to hold a place under the download limit.
+    reserved: bool,
+    /// Takes the last place under the download limit.
+    last: bool,
+    finished: bool,
+}
+
+impl Download {
+    /// Settles the download once the body of `resp` is done.
+    fn track(mut self, resp: Response) -> Response {
+        let (parts, body) = resp.into_parts();
+        // hyper drops bodies of a known length without polling them to the end
+        let length = parts
+            .headers
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok());
+        let mut sent = 0;
+        let mut body = body.into_data_stream();
+        let body = futures::stream::poll_fn(move |cx| {
+            // the stream owns the whole download, so it's settled when the stream drops
+            let download = &mut self;
+            let chunk = futures::ready!(body.poll_next_unpin(cx));
+            match &chunk {
+                Some(Ok(data)) => {
+                    sent += data.len() as u64;
+                    download.finished = length == Some(sent);
+                }
+                Some(Err(_)) => download.finished = false,
+                None => download.finished = true,
+            }
+            Poll::Ready(chunk)
+        });
+
+        Response::from_parts(parts, warp::reply::stream(body).into_response().into_body())
+    }
+}
+
+impl Drop for Download {
+    fn drop(&mut self) {
+        let state = self.state.clone();
+        let name = std::mem::take(&mut self.name);
+        let (reserved, last, finished) = (self.reserved, self.last, self.finished);
+
+        tokio::spawn(async move {
+            if finished && last {
+                info!("Deleting {name} after its last download");
+                if let Err(e) = state.remove_upload(&name).await {
+                    warn!("Failed to delete file {name}: {e}");
+                }
+            } else if finished && !reserved {
+                // only informational, so not worth a sync
+                let counted = state
+                    .metadata
+                    .update_unsynced(&name, |metadata| metadata.downloads += 1)
+                    .await;
+                if let Err(e) = counted {
+                    warn!("Error counting download of {name}: {e}");
+                }
+            } else if !finished && reserved {
+                let released = state
+                    .metadata
+                    .update(&name, |metadata| {
+                        metadata.downloads = metadata.downloads.saturating_sub(1);
+                    })
+                    .await;
+                if let Err(e) = released {
+                    warn!("Error giving back the download of {name}: {e}");
+                }
+            }
+        });
+    }
+}
+
+/// Deletes an upload given either its delete token or an upload token that may delete.
+async fn delete_file(state: State, name: String, auth: String) -> Result<impl Reply, Rejection> {
+    if !is_valid_upload_name(&name) || !state.dir.join(&name).is_file() {
+        return Err(warp::reject::not_found());
+    }
+    let token = auth
+        .strip_prefix("Bearer ")
+        .ok_or_else(warp::reject::not_found)?;
+
+    let deleted_by = match state.settings().tokens.authenticate(token) {
+        Some(policy) if policy.may_delete => policy.label.clone(),
+        _ => {
+            let matches_delete_token = state
+                .metadata
+                .read(&name)
+                .await
+                .map_err(|e| {
+                    warn!("Error reading metadata of {name}: {e}");
+                    warp::reject::custom(ServerError)
+                })?
+                .and_then(|metadata| metadata.delete_token_hash)
+                .is_some_and(|hash| hashes_match(&hash, &hash_token(token)));
+            if !matches_delete_token {
+                return Err(warp::reject::not_found());
+            }
+            "delete token".to_string()
+        }
+    };
+
+    info!("Deleting {name} on request of {deleted_by}");
+    state.remove_upload(&name).await.map_err(|e| {
+        warn!("Failed to delete file {name}: {e}");
+        warp::reject::custom(ServerError)
+    })?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn upload_file(
+    state: State,
+    ext: String,
+    policy: Arc<TokenPolicy>,
+    options: UploadOptions,
+    content_length: Option<u64>,
+    body: impl Stream<Item = Result<impl Buf, warp::Error>>,
+) -> Result<FinishedUpload> {
+    // the body is only read below, so this refuses oversized uploads before receiving them
+    let max_upload_size = max_upload_size(&state.settings(), &policy);
+    if content_length
+        .zip(max_upload_size)
+        .is_some_and(|(len, max)| len > max)
+    {
+        return Err(TooLarge.into());
+    }
+    check_name_available(&state, &ext, &options)?;
+    let mut reservation = state.quota.reservation();
+    if let Some(len) = content_length {
+        reservation.grow(len).await?;
+    }
+
+    let (mut file, temp_path) = create_temp_file(&state.dir).await?;
+    debug!("writing {temp_path:?}");
+
+    let mut hasher = Sha256::new();
+    let mut bytes_written = 0;
+    write_body(
+        &mut file,
+        body,
+        &mut hasher,
+        &mut bytes_written,
+        max_upload_size,
+        &mut reservation,
+    )
+    .await?;
+    file.sync_all().await?;
+    let content_hash = to_hex(&hasher.finalize());
+
+    let received = ReceivedUpload {
+        temp_path,
+        content_hash,
+        size: bytes_written,
+        reservation,
+    };
+    finish_upload(&state, &policy, &ext, options, received).await
+}
+
+/// Starts a resumable upload of `length` bytes, reserving quota for all of it.
+///
+/// Returns the id of the new session.
+async fn create_session(
+    state: State,
+    ext: String,
+    policy: Arc<TokenPolicy>,
+    options: UploadOptions,
+    length: u64,
+) -> Result<String> {
+    if max_upload_size(&state.settings(), &policy).is_some_and(|max| length > max) {
+        return Err(TooLarge.into());
+    }
+    check_name_available(&state, &ext, &options)?;
+    let mut reservation = state.quota.reservation();
+    reservation.grow(length).await?;
+
+    let id = state
+        .sessions
+        .create(ext, options, policy, length, reservation)
+        .await?;
+    debug!("started resumable upload {id} of {length} bytes");
+
+    Ok(id)
+}
+
+/// How much of a resumable upload has arrived, and how much is expected in total.
+async fn session_progress(
+    state: State,
+    id: String,
+    policy: Arc<TokenPolicy>,
+) -> Result<(u64, u64), Rejection> {
+    let slot = state
+        .sessions
+        .get(&id)
+        .ok_or_else(warp::reject::not_found)?;
+    let session = slot
+        .try_lock()
+        .map_err(|_| warp::reject::custom(SessionBusy))?;
+    let session = session
+        .as_ref()
+        .filter(|session| session.policy.label == policy.label)
+        .ok_or_else(warp::reject::not_found)?;
+
+    Ok((session.offset, session.length))
+}
+
+/// Appends the body to a resumable upload, finishing it once all bytes have arrived.
+async fn append_to_session(
+    state: State,
+    id: String,
+    policy: Arc<TokenPolicy>,
+    offset: u64,
+    body: impl Stream<Item = Result<impl Buf, warp::Error>>,
+) -> Result<Appended, Rejection> {
+    let slot = state
+        .sessions
+        .get(&id)
+        .ok_or_else(warp::reject::not_found)?;
+    let mut slot = slot
+        .try_lock()
+        .map_err(|_| warp::reject::custom(SessionBusy))?;
+    let session = slot
+        .as_mut()
+        .filter(|session| session.policy.label == policy.label)
+        .ok_or_else(warp::reject::not_found)?;
+    if offset != session.offset {
+        return Err(warp::reject::custom(OffsetMismatch {
+            expected: session.offset,
+        }));
+    }
+
+    let result = write_body(
+        &mut session.file,
+        body,
+        &mut session.hasher,
+        &mut session.offset,
+        Some(session.length),
+        &mut session.reservation,
+    )
+    .await;
+    session.last_active = Instant::now();
+    if let Err(e) = result {
+        // whatever arrived before the error can be kept, unless it didn't make it to disk
+        let file_len = session.file.metadata().await.map(|m| m.len()).ok();
+        if file_len != Some(session.offset) {
+            warn!("Dropping resumable upload {id} after a failed write");
+            slot.take();
+            state.sessions.remove(&id);
+        }
+        return Err(reject_upload_error(e));
+    }
+
+    if session.offset < session.length {
+        return Ok(Appended::Partial {
+            offset: session.offset,
+        });
+    }
+
+    let session = slot.take().ok_or_else(warp::reject::not_found)?;
+    state.sessions.remove(&id);
+    async move {
+        session.file.sync_all().await?;
+        let content_hash = to_hex(&session.hasher.finalize());
+        let received = ReceivedUpload {
+            temp_path: session.temp_path,
+            content_hash,
+            size: session.length,
+            reservation: session.reservation,
+        };
+        finish_upload(
+            &state,
+            &session.policy,
+            &session.ext,
+            session.options,
+            received,
+        )
+        .await
+    }
+    .await
+    .map(|finished| Appended::Complete(finished, session.length))
+    .map_err(reject_upload_error)
+}
+
+async fn abort_session(
+    state: State,
+    id: String,
+    policy: Arc<TokenPolicy>,
+) -> Result<StatusCode, Rejection> {
+    let slot = state
+        .sessions
+        .get(&id)
+        .ok_or_else(warp::reject::not_found)?;
+    let mut slot = slot
+        .try_lock()
+        .map_err(|_| warp::reject::custom(SessionBusy))?;
+    if slot
+        .as_ref()
+        .is_none_or(|session| session.policy.label != policy.label)
+    {
+        return Err(warp::reject::not_found());
+    }
+
+    // dropping the session deletes its data and gives back its quota
+    slot.take();
+    state.sessions.remove(&id);
+    debug!("aborted resumable upload {id}");
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The smaller of the server-wide and per-token upload size limits.
+fn max_upload_size(settings: &Settings, policy: &TokenPolicy) -> Option<u64> {
+    settings
+        .config
+        .max_upload_size
+        .into_iter()
+        .chain(policy.max_upload_size)
+        .min()
+}
+
+/// Fails early when a requested name is taken, so the body doesn't need to be received.
+///
+/// The final rename still checks again, this only saves the transfer.
+fn check_name_available(state: &State, ext: &str, options: &UploadOptions) -> Result<()> {
+    if let Some(name) = &options.desired_name
+        && !options.suffix_on_conflict
+        && state.dir.join(format!("{name}.{ext}")).exists()
+    {
+        return Err(NameTaken.into());
+    }
+
+    Ok(())
+}
+
+/// Appends `body` to `file`, hashing what was written and counting it in `written`.
+///
+/// Fails with [`TooLarge`] instead of writing past `limit`, and with [`BodyTimeout`] if the
+/// client stops sending for [`BODY_READ_TIMEOUT`]. Everything received before an error is
+/// still flushed, so `written` matches the file unless the flush fails.
+async fn write_body(
+    file: &mut File,
+    body: impl Stream<Item = Result<impl Buf, warp::Error>>,
+    hasher: &mut Sha256,
+    written: &mut u64,
+    limit: Option<u64>,
+    reservation: &mut Reservation,
+) -> Result<()> {
+    let mut writer = BufWriter::new(file);
+    let result = async {
+        tokio::pin!(body);
+        while let Some(mut buf) = tokio::time::timeout(BODY_READ_TIMEOUT, body.try_next())
+            .await
+            .map_err(|_| BodyTimeout)??
+        {
+            while buf.has_remaining() {
+                let chunk = buf.chunk();
+                let len = chunk.len() as u64;
+
+                if limit.is_some_and(|max| *written + len > max) {
+                    return Err(TooLarge.into());
+                }
+                if *written + len > reservation.size() {
+                    reservation
+                        .grow(*written + len - reservation.size())
+                        .await?;
+                }
+
+                writer.write_all(chunk).await?;
+                hasher.update(chunk);
+                *written += len;
+                buf.advance(chunk.len());
+            }
+        }
+        Ok(())
+    }
+    .await;
+    writer.flush().await?;
+
+    result
+}
+
+/// Where a resumable upload stands after a request appended to it.
+enum Appended {
+    Partial {
+        offset: u64,
+    },
+    /// Finished along with the total length of the upload.
+    Complete(FinishedUpload, u64),
+}
+
+/// An upload moved into place under its final name.
+#[derive(Serialize)]
+struct FinishedUpload {
+    name: String,
+    size: u64,
+    sha256: String,
+    /// What downloads of the upload are served as, going by its extension.
+    mime: String,
+    #[serde(with = "unix_time")]
+    expires_at: SystemTime,
+    delete_token: String,
+}
+
+/// The JSON body describing a [`FinishedUpload`].
+#[derive(Serialize)]
+struct UploadInfo<'a> {
+    url: String,
+    /// Where to send a `DELETE` with the delete token to remove the upload early.
+    delete_url: String,
+    #[serde(flatten)]
+    upload: &'a FinishedUpload,
+}
+
+impl FinishedUpload {
+    /// Adds what the uploader needs to know about the upload besides its name.
+    fn insert_headers(&self, headers: &mut HeaderMap) {
+        let expires_at_secs = self
+            .expires_at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        headers.insert(
+            "delete-token",
+            HeaderValue::from_str(&self.delete_token).expect("tokens are hex"),
+        );
+        headers.insert("expires-at", HeaderValue::from(expires_at_secs));
+    }
+
+    fn into_response(self, format: &ResponseFormat) -> Response {
+        let mut resp = match format {
+            ResponseFormat::Text => self.name.clone().into_response(),
+            ResponseFormat::Json { base_url } => {
+                let url = format!("{base_url}/{}", self.name);
+                warp::reply::json(&UploadInfo {
+                    delete_url: url.clone(),
+                    url,
+                    upload: &self,
+                })
+                .into_response()
+            }
+        };
+        self.insert_headers(resp.headers_mut());
+        resp
+    }
+}
+
+/// The body of an upload, completely written to a temp file.
+struct ReceivedUpload {
+    temp_path: TempPath,
+    content_hash: String,
+    size: u64,
+    reservation: Reservation,
+}
+
+/// Compresses the upload at `temp_path` into a new temp file, unless that doesn't make
+/// it any smaller.
+async fn compress_upload(
+    state: &State,
+    temp_path: &Path,
+    encoding: Encoding,
+    size: u64,
+) -> Result<Option<(TempPath, u64)>> {
+    let (mut f, compressed_path) = create_temp_file(&state.dir).await?;
+    let compressed_size = compress_file(temp_path, &mut f, encoding).await?;
+
+    Ok((compressed_size < size).then_some((compressed_path, compressed_size)))
+}
+
+/// Encrypts the upload at `temp_path` into a new temp file.
+async fn encrypt_upload(
+    state: &State,
+    temp_path: &Path,
+    key: &EncryptionKey,
+) -> Result<(TempPath, u64)> {
+    let (mut f, encrypted_path) = create_temp_file(&state.dir).await?;
+    let encrypted_size = encrypt(File::open(temp_path).await?, &mut f, key).await?;
+
+    Ok((encrypted_path, encrypted_size))
+}
+
+/// Moves a complete upload into place under a new name and records its metadata.
+async fn finish_upload(
+    state: &State,
+    policy: &TokenPolicy,
+    ext: &str,
+    options: UploadOptions,
+    received: ReceivedUpload,
+) -> Result<FinishedUpload> {
+    let ReceivedUpload {
+        mut temp_path,
+        content_hash,
+        size,
+        mut reservation,
+    } = received;
+    let settings = state.settings();
+    let (strategy, max_attempts): (Arc<dyn NamingStrategy>, _) = match options.desired_name {
+        Some(name) => (
+            Arc::new(VanityName { name }),
+            if options.suffix_on_conflict {
+                MAX_NAME_ATTEMPTS
+            } else {
+                1
+            },
+        ),
+        None => (
+            settings
+                .naming
+                .strategy(options.naming.unwrap_or(settings.config.naming)),
+            MAX_NAME_ATTEMPTS,
+        ),
+    };
+    let mut retention = settings.config.retention.resolve(options.expires_in);
+    if let Some(max_retention) = policy.max_retention {
+        retention = retention.min(max_retention);
+    }
+    let uploaded_at = SystemTime::now();
+    let expires_at = uploaded_at + retention;
+    let delete_token = generate_token();
+    let mime = guess_mime(ext);
+    let mut encoding = None;
+    if let Some(compression) = settings.config.compression
+        && is_compressible(&mime)
+    {
+        match compress_upload(state, &temp_path, compression, size).await {
+            Ok(Some((compressed_path, compressed_size))) => {
+                debug!("compressed upload from {size} to {compressed_size} bytes");
+                temp_path = compressed_path;
+                encoding = Some(compression);
+                reservation.resize(compressed_size).await?;
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Failed to compress upload, storing it as is: {e}"),
+        }
+    }
+    let mut encrypted_with = None;
+    if let Some(key) = &settings.config.encryption.current {
+        let (encrypted_path, encrypted_size) = encrypt_upload(state, &temp_path, key).await?;
+        temp_path = encrypted_path;
+        encrypted_with = Some(key.id());
+        reservation.resize(encrypted_size).await?;
+    }
+    let mut upload_metadata = Metadata::new(expires_at);
+    upload_metadata.uploaded_at = Some(uploaded_at);
+    upload_metadata.max_downloads = options.max_downloads;
+    upload_metadata.delete_token_hash = Some(hash_token(&delete_token));
+    upload_metadata.uploaded_by = Some(policy.label.clone());
+    upload_metadata.original_name = options.original_name;
+    upload_metadata.size = Some(size);
+    upload_metadata.sha256 = Some(content_hash.clone());
+    upload_metadata.mime = Some(mime.clone());
+    upload_metadata.encoding = encoding;
+    upload_metadata.encrypted_with = encrypted_with;
+
+    let naming_input = NamingInput {
+        content_hash: &content_hash,
+    };
+    let persisted = persist_upload(
+        temp_path,
+        &state.dir,
+        &state.metadata,
+        &upload_metadata,
+        max_attempts,
+        |attempt| format!("{}.{ext}", strategy.propose(&naming_input, attempt)),
+    )
+    .await?;
+    let filename = persisted.name;
+    if persisted.deduplicated {
+        debug!("{filename} shares its contents with an earlier upload");
+        drop(reservation);
+    } else {
+        reservation.commit();
+    }
+    if let Err(e) = strategy.save().await {
+        warn!("Failed to save naming state: {e}");
+    }
+    info!("{} uploaded {size} bytes to {filename}", policy.label);
+    state.expiry.schedule(filename.clone(), expires_at);
+
+    Ok(FinishedUpload {
+        mime,
+        name: filename,
+        size,
+        sha256: content_hash,
+        expires_at,
+        delete_token,
+    })
 }
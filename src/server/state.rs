@@ -0,0 +1,141 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+
+use anyhow::{Context, Result};
+use tokio::sync::Notify;
+use tracing::{info, warn};
+
+use crate::server::{
+    auth::Tokens, config::Config, expiry::Expiry, metadata::MetadataStore, naming::Naming,
+    postprocessing::Templates, quota::Quota, resumable::Sessions, storage::remove_upload,
+};
+
+/// Everything the routes share about one running server.
+#[derive(Clone)]
+pub struct State {
+    pub dir: PathBuf,
+    pub metadata: MetadataStore,
+    pub expiry: Expiry,
+    pub quota: Quota,
+    pub sessions: Sessions,
+    /// Wakes [`run_key_rotation`] after the encryption keys may have changed.
+    ///
+    /// [`run_key_rotation`]: crate::server::encryption::run_key_rotation
+    pub key_rotation: Arc<Notify>,
+    settings: Arc<RwLock<Arc<Settings>>>,
+}
+
+/// The parts of the state that are replaced when the configuration is reloaded.
+pub struct Settings {
+    pub config: Config,
+    pub tokens: Tokens,
+    pub naming: Naming,
+    pub templates: Templates,
+}
+
+impl State {
+    pub fn new(
+        dir: PathBuf,
+        metadata: MetadataStore,
+        expiry: Expiry,
+        quota: Quota,
+        sessions: Sessions,
+        settings: Settings,
+    ) -> Self {
+        Self {
+            dir,
+            metadata,
+            expiry,
+            quota,
+            sessions,
+            key_rotation: Arc::new(Notify::new()),
+            settings: Arc::new(RwLock::new(Arc::new(settings))),
+        }
+    }
+
+    /// A snapshot of the current settings, requests should hold on to one for their
+    /// whole duration so a reload doesn't change them halfway through.
+    pub fn settings(&self) -> Arc<Settings> {
+        self.settings.read().unwrap().clone()
+    }
+
+    /// Swaps in `config` once everything it refers to has loaded, keeping the current
+    /// settings if anything fails.
+    pub async fn reload(&self, config: Config) -> Result<()> {
+        config.validate()?;
+        let current = self.settings();
+        if config.bind != current.config.bind || config.storage_dir != current.config.storage_dir {
+            warn!("Changing bind addresses or the storage directory needs a restart");
+        }
+
+        let tokens = Tokens::load(&config)
+            .await
+            .context("failed to load tokens")?;
+        let naming = current
+            .naming
+            .reload(self.dir.clone(), &config.word_lists)
+            .await
+            .context("failed to load word lists")?;
+        let templates = Templates::load(config.templates_dir.as_deref())
+            .await
+            .context("failed to load templates")?;
+
+        self.quota
+            .set_limit(config.storage_quota, config.evict_on_quota);
+        *self.settings.write().unwrap() = Arc::new(Settings {
+            config,
+            tokens,
+            naming,
+            templates,
+        });
+        info!("Reloaded configuration");
+        self.key_rotation.notify_one();
+
+        Ok(())
+    }
+
+    pub async fn remove_upload(&self, name: &str) -> Result<()> {
+        remove_upload(&self.dir, &self.metadata, &self.quota, name).await?;
+        self.expiry.unschedule(name);
+
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_reload_keeps_settings_on_failure() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let dir = temp_dir.path().to_path_buf();
+    let config = |upload_token: &str, tokens_file: Option<PathBuf>| Config {
+        upload_token: Some(upload_token.to_string()),
+        tokens_file,
+        ..Default::default()
+    };
+
+    let metadata = MetadataStore::new(dir.clone());
+    let expiry = Expiry::load(dir.clone(), metadata.clone(), Default::default())
+        .await
+        .unwrap();
+    let quota = Quota::load(dir.clone(), metadata.clone(), expiry.clone(), None, false)
+        .await
+        .unwrap();
+    let tokens = Tokens::load(&config("old", None)).await.unwrap();
+    let sessions = Sessions::load(dir.clone(), &quota, &tokens).await.unwrap();
+    let settings = Settings {
+        config: config("old", None),
+        tokens,
+        naming: Naming::load(dir.clone(), &[]).await.unwrap(),
+        templates: Templates::default(),
+    };
+    let state = State::new(dir.clone(), metadata, expiry, quota, sessions, settings);
+
+    let missing = dir.join("missing.json");
+    assert!(state.reload(config("new", Some(missing))).await.is_err());
+    assert!(state.settings().tokens.authenticate("old").is_some());
+
+    state.reload(config("new", None)).await.unwrap();
+    assert!(state.settings().tokens.authenticate("old").is_none());
+    assert!(state.settings().tokens.authenticate("new").is_some());
+}
@@ -0,0 +1,599 @@
+use std::{
+    fmt,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use tempfile::{NamedTempFile, TempDir, TempPath};
+use tokio::fs::File;
+use tracing::{debug, warn};
+use warp::reject;
+
+use crate::server::{
+    metadata::{Metadata, MetadataStore, Replacement, sync_dir},
+    quota::Quota,
+};
+
+/// Uploads are written here first and only renamed into place once complete.
+pub const TEMP_DIR: &str = ".tmp";
+/// Contents of uploads are kept once per SHA-256 here, every upload with the same
+/// contents being a hard link to the same blob.
+pub const BLOB_DIR: &str = ".blobs";
+/// Rewritten contents of uploads wait here until they replace the old ones.
+pub const REPLACE_DIR: &str = ".replace";
+
+pub enum Storage {
+    /// Uploads are kept in a directory that survives restarts.
+    Persistent(PathBuf),
+    /// Uploads are kept in a temporary directory that is deleted on shutdown.
+    Ephemeral(TempDir),
+}
+
+impl Storage {
+    pub async fn persistent<P: Into<PathBuf>>(dir: P) -> Result<Self> {
+        let dir = dir.into();
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .with_context(|| format!("failed to create storage directory {dir:?}"))?;
+        let dir = tokio::fs::canonicalize(&dir).await?;
+
+        // anything left here was cut off by a crash
+        match tokio::fs::remove_dir_all(dir.join(TEMP_DIR)).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                warn!("Failed to clean up partial uploads: {e}");
+            }
+            _ => {}
+        }
+        if let Err(e) = remove_unused_blobs(&dir).await {
+            warn!("Failed to clean up unused blobs: {e}");
+        }
+
+        Ok(Self::Persistent(dir))
+    }
+
+    pub fn ephemeral() -> Result<Self> {
+        let temp_dir = tempfile::tempdir().context("failed to create temporary directory")?;
+
+        Ok(Self::Ephemeral(temp_dir))
+    }
+
+    pub fn path(&self) -> &Path {
+        match self {
+            Self::Persistent(dir) => dir,
+            Self::Ephemeral(temp_dir) => temp_dir.path(),
+        }
+    }
+
+    pub fn close(self) {
+        if let Self::Ephemeral(temp_dir) = self
+            && let Err(e) = temp_dir.close()
+        {
+            warn!("Failed to clean up temporary directory: {e}");
+        }
+    }
+}
+
+/// Internal bookkeeping lives in dot-directories that must never be served or expired.
+pub fn is_hidden_name(name: &str) -> bool {
+    name.starts_with('.')
+}
+
+/// Whether `name` could have been handed out for an upload, e.g. `word-word-word.ext`.
+pub fn is_valid_upload_name(name: &str) -> bool {
+    !name.is_empty()
+        && !is_hidden_name(name)
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+}
+
+pub async fn remove_upload(
+    dir: &Path,
+    metadata: &MetadataStore,
+    quota: &Quota,
+    name: &str,
+) -> Result<()> {
+    let blob = metadata
+        .read(name)
+        .await
+        .ok()
+        .flatten()
+        .and_then(|metadata| blob_name(&metadata));
+
+    let path = dir.join(name);
+    let file_metadata = match tokio::fs::metadata(&path).await {
+        Ok(file_metadata) => Some(file_metadata),
+        Err(e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+    match tokio::fs::remove_file(&path).await {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    if let Some(file_metadata) = file_metadata {
+        if link_count(&file_metadata).is_none_or(|links| links <= 1) {
+            quota.release(file_metadata.len());
+        } else if let Some(blob) = blob {
+            // the contents are only gone once the blob is the last name left for them
+            let blob = dir.join(BLOB_DIR).join(blob);
+            if let Ok(blob_metadata) = tokio::fs::metadata(&blob).await
+                && link_count(&blob_metadata) == Some(1)
+            {
+                debug!("reclaiming blob {blob:?}");
+                tokio::fs::remove_file(&blob).await?;
+                quota.release(blob_metadata.len());
+            }
+        }
+    }
+
+    // contents a key rotation staged for the upload
+    match tokio::fs::remove_file(dir.join(REPLACE_DIR).join(name)).await {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+
+    // the sidecar goes last, so the name stays claimed until the file is gone and a crash
+    // in between leaves a stale sidecar that is dropped on the next start
+    metadata.remove(name).await?;
+
+    Ok(())
+}
+
+/// Uploads share a blob if they have the same contents stored the same way.
+fn blob_name(upload_metadata: &Metadata) -> Option<String> {
+    let mut name = upload_metadata.sha256.clone()?;
+    if let Some(encoding) = upload_metadata.encoding {
+        name = format!("{name}.{encoding}");
+    }
+    if let Some(key_id) = &upload_metadata.encrypted_with {
+        name = format!("{name}.{key_id}");
+    }
+
+    Some(name)
+}
+
+/// How many names the file behind `file_metadata` has, if the platform tells.
+#[cfg(unix)]
+fn link_count(file_metadata: &std::fs::Metadata) -> Option<u64> {
+    Some(std::os::unix::fs::MetadataExt::nlink(file_metadata))
+}
+
+#[cfg(not(unix))]
+fn link_count(_file_metadata: &std::fs::Metadata) -> Option<u64> {
+    None
+}
+
+/// Identifies the file behind `file_metadata` across all its names, if the platform tells.
+#[cfg(unix)]
+pub fn file_id(file_metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    Some((file_metadata.dev(), file_metadata.ino()))
+}
+
+#[cfg(not(unix))]
+pub fn file_id(_file_metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// Deletes blobs no upload links to anymore, left behind by a crash while removing
+/// their last upload.
+async fn remove_unused_blobs(dir: &Path) -> Result<()> {
+    let mut entries = match tokio::fs::read_dir(dir.join(BLOB_DIR)).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        if link_count(&entry.metadata().await?) == Some(1) {
+            debug!("removing unused blob {:?}", entry.file_name());
+            tokio::fs::remove_file(entry.path()).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Creates a file in [`TEMP_DIR`] that is deleted again unless it's persisted.
+pub async fn create_temp_file(dir: &Path) -> Result<(File, TempPath)> {
+    let temp_dir = dir.join(TEMP_DIR);
+    tokio::fs::create_dir_all(&temp_dir).await?;
+    let (f, temp_path) = NamedTempFile::new_in(&temp_dir)?.into_parts();
+
+    Ok((File::from_std(f), temp_path))
+}
+
+pub const MAX_NAME_ATTEMPTS: usize = 100;
+
+#[derive(Debug)]
+pub struct NameTaken;
+
+impl fmt::Display for NameTaken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "name is already taken")
+    }
+}
+
+impl std::error::Error for NameTaken {}
+
+impl reject::Reject for NameTaken {}
+
+/// Where [`persist_upload`] put an upload.
+pub struct Persisted {
+    pub name: String,
+    /// The same contents were already stored, so the upload takes up no extra space.
+    pub deduplicated: bool,
+}
+
+/// Moves a finished upload to the first name from `next_name` that isn't taken yet,
+/// passing it the number of names tried so far.
+///
+/// Each name is claimed by creating its sidecar before the upload is moved, and the
+/// rename fails instead of replacing an existing file, so two uploads can never end up
+/// with the same name and no upload is ever visible without its metadata.
+///
+/// If a blob with the same SHA-256 and encoding as recorded in `upload_metadata` exists, the name
+/// becomes another link to it and the temp file is dropped. Otherwise the upload is
+/// linked into [`BLOB_DIR`] for later uploads to share.
+pub async fn persist_upload<F>(
+    mut temp_path: TempPath,
+    dir: &Path,
+    metadata: &MetadataStore,
+    upload_metadata: &Metadata,
+    max_attempts: usize,
+    mut next_name: F,
+) -> Result<Persisted>
+where
+    F: FnMut(usize) -> String,
+{
+    let blob = match blob_name(upload_metadata) {
+        // blobs could never be reclaimed without link counts
+        Some(blob) if cfg!(unix) => {
+            tokio::fs::create_dir_all(dir.join(BLOB_DIR)).await?;
+            Some(dir.join(BLOB_DIR).join(blob))
+        }
+        _ => None,
+    };
+
+    for attempt in 0..max_attempts {
+        let name = next_name(attempt);
+        if !metadata.create(&name, upload_metadata).await? {
+            debug!("{name} is already taken, trying another name");
+            continue;
+        }
+
+        let target = dir.join(&name);
+        let blob = blob.clone();
+        let placed = tokio::task::spawn_blocking(move || match blob {
+            Some(blob) => match std::fs::hard_link(&blob, &target) {
+                Ok(()) => Ok(true),
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    place_file(temp_path, &target, Some(&blob))
+                }
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => Err(Ok(temp_path)),
+                Err(e) => Err(Err(e)),
+            },
+            None => place_file(temp_path, &target, None),
+        })
+        .await?;
+
+        match placed {
+            Ok(deduplicated) => {
+                let dir = dir.to_path_buf();
+                tokio::task::spawn_blocking(move || sync_dir(&dir)).await??;
+                return Ok(Persisted { name, deduplicated });
+            }
+            Err(e) => {
+                // give the name back, it's still owned by whatever file is there
+                metadata.remove(&name).await?;
+                temp_path = e?;
+                debug!("{name} is already taken, trying another name");
+            }
+        }
+    }
+
+    Err(NameTaken.into())
+}
+
+/// Swaps the stored contents of upload `name` for `temp_path`, which are encrypted with
+/// the key `encrypted_with`, or not at all if unset.
+///
+/// The new contents are staged in [`REPLACE_DIR`] and the swap is recorded in the
+/// sidecar before they're moved into place, so after a crash [`recover_replacement`] can
+/// tell which contents the upload has. Like in [`persist_upload`], they're shared through
+/// [`BLOB_DIR`], and the old blob is reclaimed if this was its last upload.
+///
+/// Returns `false` if the upload was removed in the meantime.
+pub async fn replace_upload(
+    dir: &Path,
+    metadata: &MetadataStore,
+    name: &str,
+    temp_path: TempPath,
+    encrypted_with: Option<String>,
+) -> Result<bool> {
+    let Some(old_metadata) = metadata.read(name).await? else {
+        return Ok(false);
+    };
+    let mut new_metadata = old_metadata.clone();
+    new_metadata.encrypted_with = encrypted_with.clone();
+    let old_blob = blob_name(&old_metadata);
+    let new_blob = blob_name(&new_metadata);
+
+    let replace_dir = dir.join(REPLACE_DIR);
+    tokio::fs::create_dir_all(&replace_dir).await?;
+    let staged = replace_dir.join(name);
+    {
+        let staged = staged.clone();
+        tokio::task::spawn_blocking(move || -> Result<()> {
+            temp_path.persist(&staged)?;
+            sync_dir(staged.parent().context("parent() None")?)
+        })
+        .await??;
+    }
+
+    let replacement = Replacement {
+        encrypted_with: encrypted_with.clone(),
+    };
+    if metadata
+        .update(name, |metadata| metadata.replacing = Some(replacement))
+        .await?
+        .is_none()
+    {
+        tokio::fs::remove_file(&staged).await?;
+        return Ok(false);
+    }
+
+    if cfg!(unix)
+        && let Some(blob) = &new_blob
+    {
+        tokio::fs::create_dir_all(dir.join(BLOB_DIR)).await?;
+        let blob = dir.join(BLOB_DIR).join(blob);
+        let staged = staged.clone();
+        tokio::task::spawn_blocking(move || share_blob(&staged, &blob)).await??;
+    }
+
+    let target = dir.join(name);
+    let replaced = metadata
+        .update(name, |metadata| -> Result<bool> {
+            // removing an upload deletes its file before the sidecar, so don't bring it back
+            if !target.exists() {
+                return Ok(false);
+            }
+            std::fs::rename(&staged, &target)?;
+            metadata.encrypted_with = encrypted_with;
+            metadata.replacing = None;
+            Ok(true)
+        })
+        .await?
+        .transpose()?
+        .unwrap_or(false);
+    if !replaced {
+        match tokio::fs::remove_file(&staged).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+            _ => return Ok(false),
+        }
+    }
+    let synced_dir = dir.to_path_buf();
+    tokio::task::spawn_blocking(move || sync_dir(&synced_dir)).await??;
+
+    if let Some(old_blob) = old_blob
+        && Some(&old_blob) != new_blob.as_ref()
+    {
+        let old_blob = dir.join(BLOB_DIR).join(old_blob);
+        if let Ok(blob_metadata) = tokio::fs::metadata(&old_blob).await
+            && link_count(&blob_metadata) == Some(1)
+        {
+            debug!("reclaiming blob {old_blob:?}");
+            tokio::fs::remove_file(&old_blob).await?;
+        }
+    }
+
+    Ok(true)
+}
+
+/// Makes `staged` share `blob` if another upload with the same contents was already
+/// rewritten, or offers it as that blob otherwise.
+fn share_blob(staged: &Path, blob: &Path) -> Result<()> {
+    let mut link = staged.as_os_str().to_owned();
+    link.push(".link");
+    match std::fs::hard_link(blob, &link) {
+        Ok(()) => std::fs::rename(&link, staged)?,
+        Err(e) if e.kind() == ErrorKind::NotFound => match std::fs::hard_link(staged, blob) {
+            Err(e) if e.kind() != ErrorKind::AlreadyExists => {
+                warn!("Failed to store blob {blob:?}: {e}");
+            }
+            _ => {}
+        },
+        Err(e) => return Err(e.into()),
+    }
+
+    Ok(())
+}
+
+/// Settles a [`replace_upload`] of `name` that was cut off by a crash, going by whether
+/// its staged contents were moved into place yet.
+pub async fn recover_replacement(dir: &Path, metadata: &MetadataStore, name: &str) -> Result<()> {
+    let staged = dir.join(REPLACE_DIR).join(name);
+    let moved = !tokio::fs::try_exists(&staged).await?;
+    metadata
+        .update(name, |metadata| {
+            if let Some(replacement) = metadata.replacing.take()
+                && moved
+            {
+                metadata.encrypted_with = replacement.encrypted_with;
+            }
+        })
+        .await?;
+    match tokio::fs::remove_file(&staged).await {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Deletes contents staged in [`REPLACE_DIR`] for uploads that aren't being replaced
+/// anymore, e.g. because they were removed.
+pub async fn remove_stale_replacements(dir: &Path, metadata: &MetadataStore) -> Result<()> {
+    let mut entries = match tokio::fs::read_dir(dir.join(REPLACE_DIR)).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        let replacing = metadata
+            .read(&name)
+            .await?
+            .is_some_and(|metadata| metadata.replacing.is_some());
+        if !replacing {
+            debug!("removing stale replacement {name}");
+            tokio::fs::remove_file(entry.path()).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The key the stored contents of upload `name` are encrypted with, if any.
+///
+/// While [`replace_upload`] swaps them, they're the new ones once they're no longer staged.
+pub fn encrypted_with<'a>(
+    dir: &Path,
+    name: &str,
+    upload_metadata: &'a Metadata,
+) -> Option<&'a str> {
+    match &upload_metadata.replacing {
+        Some(replacement) if !dir.join(REPLACE_DIR).join(name).exists() => {
+            replacement.encrypted_with.as_deref()
+        }
+        _ => upload_metadata.encrypted_with.as_deref(),
+    }
+}
+
+/// Moves the upload to `target`, then links it as `blob` for later uploads to share.
+/// Blocks, so it's run with [`tokio::task::spawn_blocking`].
+///
+/// Fails with the temp file if `target` is taken. The upload takes its own name first so
+/// a blob being reclaimed at the same time can never take its contents along.
+fn place_file(
+    temp_path: TempPath,
+    target: &Path,
+    blob: Option<&Path>,
+) -> Result<bool, io::Result<TempPath>> {
+    match temp_path.persist_noclobber(target) {
+        Ok(()) => {}
+        Err(e) if e.error.kind() == ErrorKind::AlreadyExists => return Err(Ok(e.path)),
+        Err(e) => return Err(Err(e.error)),
+    }
+
+    if let Some(blob) = blob {
+        match std::fs::hard_link(target, blob) {
+            // stored by a concurrent upload, this one just doesn't get shared
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+            Err(e) => warn!("Failed to store blob {blob:?}: {e}"),
+            Ok(()) => {}
+        }
+    }
+
+    Ok(false)
+}
+
+#[tokio::test]
+async fn test_persist_upload_skips_taken_names() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let dir = temp_dir.path();
+    let metadata = MetadataStore::new(dir.to_path_buf());
+    let upload_metadata = Metadata::new(std::time::SystemTime::now());
+    std::fs::write(dir.join("taken.txt"), "old").unwrap();
+    metadata
+        .write(
+            "claimed.txt",
+            &Metadata::new(std::time::SystemTime::UNIX_EPOCH),
+        )
+        .await
+        .unwrap();
+
+    let mut temp_file = tempfile::NamedTempFile::new_in(dir).unwrap();
+    std::io::Write::write_all(&mut temp_file, b"new").unwrap();
+
+    let mut names = ["taken.txt", "claimed.txt", "free.txt"].into_iter();
+    let persisted = persist_upload(
+        temp_file.into_temp_path(),
+        dir,
+        &metadata,
+        &upload_metadata,
+        3,
+        |_| names.next().unwrap().to_string(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(persisted.name, "free.txt");
+    assert_eq!(
+        std::fs::read_to_string(dir.join("taken.txt")).unwrap(),
+        "old"
+    );
+    assert!(metadata.read("taken.txt").await.unwrap().is_none());
+    assert!(!dir.join("claimed.txt").exists());
+    assert_eq!(
+        std::fs::read_to_string(dir.join("free.txt")).unwrap(),
+        "new"
+    );
+    assert!(metadata.read("free.txt").await.unwrap().is_some());
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_identical_uploads_share_a_blob() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let dir = temp_dir.path();
+    let metadata = MetadataStore::new(dir.to_path_buf());
+    let expiry = crate::server::expiry::Expiry::load(
+        dir.to_path_buf(),
+        metadata.clone(),
+        Default::default(),
+    )
+    .await
+    .unwrap();
+    let quota = Quota::load(dir.to_path_buf(), metadata.clone(), expiry, None, false)
+        .await
+        .unwrap();
+    let mut upload_metadata = Metadata::new(std::time::SystemTime::now());
+    upload_metadata.sha256 = Some("abc".to_string());
+
+    let mut deduplicated = Vec::new();
+    for name in ["first.txt", "second.txt"] {
+        let mut temp_file = tempfile::NamedTempFile::new_in(dir).unwrap();
+        std::io::Write::write_all(&mut temp_file, b"same").unwrap();
+        let persisted = persist_upload(
+            temp_file.into_temp_path(),
+            dir,
+            &metadata,
+            &upload_metadata,
+            1,
+            |_| name.to_string(),
+        )
+        .await
+        .unwrap();
+        deduplicated.push(persisted.deduplicated);
+    }
+    assert_eq!(deduplicated, [false, true]);
+
+    let blob = dir.join(BLOB_DIR).join("abc");
+    assert_eq!(link_count(&std::fs::metadata(&blob).unwrap()), Some(3));
+    assert_eq!(
+        std::fs::read_to_string(dir.join("second.txt")).unwrap(),
+        "same"
+    );
+
+    remove_upload(dir, &metadata, &quota, "first.txt")
+        .await
+        .unwrap();
+    assert!(blob.exists());
+    remove_upload(dir, &metadata, &quota, "second.txt")
+        .await
+        .unwrap();
+    assert!(!blob.exists());
+}
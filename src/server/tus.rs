@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result, bail};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use warp::{
+    Filter,
+    reject::{self, Rejection},
+};
+
+use crate::UNKNOWN_EXT;
+
+/// Version of the [tus](https://tus.io/protocols/resumable-upload) protocol we speak.
+pub const TUS_VERSION: &str = "1.0.0";
+pub const TUS_EXTENSIONS: &str = "creation,termination";
+/// Content type tus clients must send with `PATCH` requests.
+pub const OFFSET_CONTENT_TYPE: &str = "application/offset+octet-stream";
+
+/// The client speaks a tus version other than [`TUS_VERSION`].
+#[derive(Debug)]
+pub struct UnsupportedVersion;
+
+impl reject::Reject for UnsupportedVersion {}
+
+/// A `PATCH` request without [`OFFSET_CONTENT_TYPE`].
+#[derive(Debug)]
+pub struct UnsupportedMediaType;
+
+impl reject::Reject for UnsupportedMediaType {}
+
+/// Requires the `Tus-Resumable` header every tus request except `OPTIONS` must send.
+pub fn tus_resumable() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("tus-resumable")
+        .and_then(|version: Option<String>| async move {
+            if version.as_deref() == Some(TUS_VERSION) {
+                Ok(())
+            } else {
+                Err(reject::custom(UnsupportedVersion))
+            }
+        })
+        .untuple_one()
+}
+
+pub fn offset_content_type() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("content-type")
+        .and_then(|content_type: Option<String>| async move {
+            if content_type.is_some_and(|content_type| content_type == OFFSET_CONTENT_TYPE) {
+                Ok(())
+            } else {
+                Err(reject::custom(UnsupportedMediaType))
+            }
+        })
+        .untuple_one()
+}
+
+/// Parses `Upload-Metadata`, a comma separated list of keys each followed by an optional
+/// base64 encoded value.
+pub fn parse_metadata(header: &str) -> Result<HashMap<String, String>> {
+    let mut metadata = HashMap::new();
+    for pair in header
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+    {
+        let (key, value) = match pair.split_once(' ') {
+            Some((key, value)) => {
+                let value = BASE64_STANDARD
+                    .decode(value.trim())
+                    .with_context(|| format!("metadata value of {key:?} is not base64"))?;
+                let value = String::from_utf8(value)
+                    .with_context(|| format!("metadata value of {key:?} is not utf8"))?;
+                (key, value)
+            }
+            None => (pair, String::new()),
+        };
+        if metadata.insert(key.to_string(), value).is_some() {
+            bail!("metadata key {key:?} is given twice");
+        }
+    }
+
+    Ok(metadata)
+}
+
+/// Picks the extension of an upload from the `ext` metadata our own client sends, or the
+/// `filename` or `filetype` metadata that clients like tus-js-client send, falling back
+/// to [`UNKNOWN_EXT`].
+pub fn ext_from_metadata(metadata: &HashMap<String, String>, max_len: usize) -> String {
+    let is_valid = |ext: &str| {
+        !ext.is_empty() && ext.len() <= max_len && ext.chars().all(|c| c.is_ascii_alphanumeric())
+    };
+
+    if let Some(ext) = metadata.get("ext")
+        && is_valid(ext)
+    {
+        return ext.to_string();
+    }
+    if let Some((_, ext)) = metadata
+        .get("filename")
+        .and_then(|filename| filename.rsplit_once('.'))
+        && is_valid(ext)
+    {
+        return ext.to_string();
+    }
+    if let Some(ext) = metadata
+        .get("filetype")
+        .and_then(|mime| mime_guess::get_mime_extensions_str(mime))
+        .and_then(|exts| exts.first())
+        && is_valid(ext)
+    {
+        return ext.to_string();
+    }
+
+    UNKNOWN_EXT.to_string()
+}
+
+#[test]
+fn test_parse_metadata() {
+    let metadata = parse_metadata("filename cmVjb3JkaW5nLm1rdg==, is_confidential").unwrap();
+    assert_eq!(metadata["filename"], "recording.mkv");
+    assert_eq!(metadata["is_confidential"], "");
+    assert_eq!(ext_from_metadata(&metadata, 10), "mkv");
+    assert_eq!(ext_from_metadata(&metadata, 2), UNKNOWN_EXT);
+
+    let metadata = parse_metadata("filetype aW1hZ2UvcG5n").unwrap();
+    assert_eq!(ext_from_metadata(&metadata, 10), "png");
+
+    let metadata = parse_metadata("ext dHh0, filename bm90ZXMubWQ=").unwrap();
+    assert_eq!(ext_from_metadata(&metadata, 10), "txt");
+
+    assert!(parse_metadata("filename !!!").is_err());
+    assert!(parse_metadata("a, a").is_err());
+}
@@ -0,0 +1,41 @@
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Deserializer, Serializer, ser::Error};
+
+/// Writes `time` as whole seconds since the unix epoch, for `#[serde(with = "unix_time")]`.
+pub fn serialize<S: Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_err(S::Error::custom)?
+        .as_secs();
+    serializer.serialize_u64(secs)
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SystemTime, D::Error> {
+    let secs = u64::deserialize(deserializer)?;
+    Ok(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+/// The same for optional times, for `#[serde(with = "unix_time::option")]`.
+pub mod option {
+    use std::time::{Duration, SystemTime};
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        time: &Option<SystemTime>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match time {
+            Some(time) => super::serialize(time, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<SystemTime>, D::Error> {
+        let secs = Option::<u64>::deserialize(deserializer)?;
+        Ok(secs.map(|secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs)))
+    }
+}